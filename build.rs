@@ -0,0 +1,64 @@
+use std::env;
+use std::fs;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// build information for `cc-server version`
+fn main() {
+    let git_hash = output("git", &["rev-parse", "--short", "HEAD"]);
+    let rustc_version = output(&env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string()), &["--version"]);
+    let build_time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+    let lock = fs::read_to_string("Cargo.lock").unwrap_or_default();
+
+    println!("cargo:rustc-env=GIT_HASH={}", git_hash);
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_time);
+    println!("cargo:rustc-env=RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=TARGET={}", env::var("TARGET").unwrap_or_else(|_| "unknown".to_string()));
+    println!("cargo:rustc-env=REDB_VERSION={}", locked_version(&lock, "redb"));
+    println!("cargo:rustc-env=AXUM_VERSION={}", locked_version(&lock, "axum"));
+
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    // HEAD only changes on checkout, a commit moves the branch it points to, which is either its own file or in packed-refs
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    let head = fs::read_to_string(".git/HEAD").unwrap_or_default();
+    if let Some(branch) = head.trim().strip_prefix("ref: ") {
+        let path = format!(".git/{}", branch);
+        // a packed branch only gets its own file on the next commit, which shows up as a change to the directory
+        if fs::metadata(&path).is_ok() {
+            watch(&path);
+        } else if let Some((dir, _)) = path.rsplit_once('/') {
+            watch(dir);
+        }
+    }
+    watch(".git/packed-refs");
+}
+
+/// Reruns the build script when `path` changes. Missing files are skipped, since cargo would rerun on every build for them.
+fn watch(path: &str) {
+    if fs::metadata(path).is_ok() {
+        println!("cargo:rerun-if-changed={}", path);
+    }
+}
+
+/// Trimmed stdout of a command, or "unknown" if it couldn't be run or failed.
+fn output(cmd: &str, args: &[&str]) -> String {
+    Command::new(cmd).args(args).output().ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| String::from_utf8(o.stdout).ok())
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Version of a package in Cargo.lock, or "unknown".
+fn locked_version(lock: &str, package: &str) -> String {
+    let name = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    while let Some(line) = lines.next() {
+        if line == name && let Some(version) = lines.next().and_then(|l| l.strip_prefix("version = \"")) {
+            return version.trim_end_matches('"').to_string();
+        }
+    }
+    "unknown".to_string()
+}
@@ -1,52 +1,407 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
 use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::net::SocketAddr;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::process::Stdio;
+use std::sync::{Arc, LazyLock, Mutex, OnceLock, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use base64::Engine;
-use clap::Parser;
-use rand::RngExt;
-use redb::{Database, ReadableDatabase, ReadableTable, ReadableTableMetadata, TableDefinition, TableHandle};
-use serde::Serialize;
+use clap::{Args, Parser, ValueEnum};
+use rand::{RngExt, SeedableRng};
+use rand::rngs::StdRng;
+use redb::{
+    Database, Key, ReadOnlyTable, ReadTransaction, ReadableDatabase, ReadableTable, ReadableTableMetadata,
+    StorageError, Table, TableDefinition, TableError, TableHandle, Value
+};
+use serde::{Deserialize, Serialize};
 use anyhow::Result;
+use arc_swap::ArcSwapOption;
 use axum::{
     body::Bytes,
     Router,
-    extract::{State, Path},
+    extract::{ConnectInfo, OriginalUri, State, Path, Query, Request},
+    Extension,
     Json,
-    http::{StatusCode, Uri},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri},
+    middleware::{self, Next},
     response::{Html, IntoResponse, Redirect, Response as AxumResponse},
     routing::{get, post}
 };
-use axum::http::header::LOCATION;
+use axum::http::header::{
+    AUTHORIZATION, CONTENT_LENGTH, CONTENT_SECURITY_POLICY, CONTENT_TYPE, COOKIE, ETAG, HOST, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, LAST_MODIFIED, LOCATION, REFERRER_POLICY, RETRY_AFTER, SERVER, X_CONTENT_TYPE_OPTIONS
+};
+use listenfd::ListenFd;
 use tokio::net::TcpListener;
+use tokio::time::MissedTickBehavior;
+use tower_http::timeout::TimeoutLayer;
 
 #[derive(Debug, Clone, Parser)]
 #[command(author, version, about)]
 struct Cli {
-    /// Path to the database file.
-    #[arg()]
-    db: PathBuf,
+    /// Path to the database file. Required for everything except `version`.
+    #[arg(env = "CC_DB")]
+    db: Option<PathBuf>,
+
+    /// Read the database path from this environment variable instead.
+    #[arg(long, value_name = "ENV_VAR", conflicts_with = "db")]
+    db_path_env: Option<String>,
+
+    /// Prepended to the names of all of cc's tables (like `myapp_c2u`), for sharing a database file with other data.
+    /// Every command run against the database has to use the same prefix.
+    #[arg(long, global = true, default_value = "", env = "CC_TABLE_PREFIX")]
+    table_prefix: String,
 
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Debug, Clone, Args)]
+struct ServeArgs {
+    /// Base URL to serve on.
+    #[arg(long, default_value = "127.0.0.1:8080", env = "CC_URL")]
+    url: SocketAddr,
+
+    /// Serve Prometheus metrics at `/metrics` on this port (same address as `--url`), and nothing else.
+    /// Metrics aren't served on the main port.
+    #[arg(long, env = "CC_METRICS_PORT")]
+    metrics_port: Option<u16>,
+
+    /// Upper bounds, in seconds, of the `GET /{code}` and `/put` latency histogram buckets on `--metrics-port`.
+    /// Comma-separated, positive and strictly increasing.
+    #[arg(long, default_value = DEFAULT_LATENCY_BUCKETS, value_parser = parse_latency_buckets, env = "CC_LATENCY_HISTOGRAM_BUCKETS")]
+    latency_histogram_buckets: LatencyBuckets,
+
+    /// Write a json snapshot of the counters to this file every `--metrics-interval` seconds,
+    /// for setups without a Prometheus scraper.
+    #[arg(long, value_name = "FILE", env = "CC_METRICS_FILE")]
+    metrics_file: Option<PathBuf>,
+
+    /// Seconds between `--metrics-file` snapshots.
+    #[arg(long, default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..), requires = "metrics_file", env = "CC_METRICS_INTERVAL")]
+    metrics_interval: u64,
+
+    /// Path to an html file to serve on the root path.
+    #[arg(long, env = "CC_INDEX")]
+    index: Option<PathBuf>,
+
+    /// Serve the built-in frontend on the root path. `--index` takes priority.
+    #[arg(long, env = "CC_UI")]
+    ui: bool,
+
+    /// Open the server in the default browser once it's listening, for trying it out locally.
+    /// Skipped (with a warning) in CI or without a display.
+    #[arg(long, env = "CC_OPEN")]
+    open: bool,
+
+    /// Use a listening socket passed in by systemd (socket activation) instead of binding `--url`.
+    /// Falls back to binding `--url` if no socket was passed.
+    #[arg(long, env = "CC_SYSTEMD")]
+    systemd: bool,
+
+    /// Fixed string to prepend to every generated code, e.g. `prod-`.
+    /// Only url-safe characters (`A-Z`, `a-z`, `0-9`, `-`, `_`), at most 16 bytes.
+    #[arg(long, default_value = "", env = "CC_CODE_PREFIX")]
+    code_prefix: String,
+
+    /// How generated codes look.
+    #[arg(long, value_enum, default_value_t = CodeProfile::Base64, env = "CC_CODE_PROFILE")]
+    code_profile: CodeProfile,
+
+    /// Seed the code generator, so the same requests always get the same codes. For tests only:
+    /// seeded codes are predictable.
+    #[arg(long, hide = true, env = "CC_SEED")]
+    seed: Option<u64>,
+
+    /// File of codes (one per line) never to generate, e.g. offensive words. Matching ignores case.
+    /// Reloaded on SIGHUP.
+    #[arg(long, value_name = "FILE", env = "CC_CODE_RESERVED")]
+    code_reserved: Option<PathBuf>,
+
+    /// Also store urls exactly as submitted and redirect to those.
+    /// Deduplication still uses the normalized url.
+    #[arg(long, env = "CC_KEEP_ORIGINAL")]
+    keep_original: bool,
+
+    /// Store and deduplicate urls exactly as submitted (after trimming), skipping normalization.
+    /// They're still validated, but urls that differ only in case, like `http://EXAMPLE.COM/path` and
+    /// `http://example.com/path`, get separate codes.
+    #[arg(long, env = "CC_NO_DOUBLE_SLASH_NORMALIZE")]
+    no_double_slash_normalize: bool,
+
+    /// Seconds between background database health checks, 0 to disable.
+    /// `/health` returns 503 while the last check failed.
+    #[arg(long, default_value_t = 0, env = "CC_DB_CHECK_INTERVAL")]
+    db_check_interval: u64,
+
+    /// The public url this server is reached at, e.g. `https://cc.example.com`.
+    /// Used to spot urls that point back at this server.
+    #[arg(long, value_name = "URL", env = "CC_BASE_URL")]
+    base_url: Option<url::Url>,
+
+    /// With `--base-url`, how many short links to follow from a new url looking for a redirect cycle.
+    /// Longer chains get a warning (and are refused anyway, since they point back at this server).
+    #[arg(long, default_value_t = 5, requires = "base_url", env = "CC_REDIRECT_LOOP_MAX")]
+    redirect_loop_max: usize,
+
+    /// Accept relative urls on `/put`, resolved against this base url. Only for trusted internal use.
+    #[arg(long, value_name = "URL", env = "CC_RELATIVE_BASE")]
+    relative_base: Option<url::Url>,
+
+    /// Status codes for successful `/put`s: `split` sends 201 for new codes and 200 when the url already
+    /// had one, `ok` always sends 200, and `created` always sends 201.
+    #[arg(long, value_enum, default_value_t = PutStatus::Split, env = "CC_PUT_STATUS")]
+    put_status: PutStatus,
+
+    /// Reject urls with no path (just a domain, like `https://example.com/`).
+    #[arg(long, env = "CC_DENY_EMPTY_PATH_TARGETS")]
+    deny_empty_path_targets: bool,
+
+    /// Refuse urls whose percent-encoded parts decode to ill-formed utf-8 (like encoded surrogates) or noncharacters,
+    /// or with private use characters in the host.
+    #[arg(long, env = "CC_VALIDATE_UTF8_STRICT")]
+    validate_utf8_strict: bool,
+
+    /// Value of the `X-Robots-Tag` header sent with successful responses and redirects,
+    /// to keep search engines from indexing redirect targets.
+    #[arg(long, default_value = "noindex,nofollow", env = "CC_X_ROBOTS_TAG")]
+    x_robots_tag: String,
+
+    /// Don't send an `X-Robots-Tag` header.
+    #[arg(long, conflicts_with = "x_robots_tag", env = "CC_NO_X_ROBOTS_TAG")]
+    no_x_robots_tag: bool,
+
+    /// Answer every request except `/health` with 503, while keeping the port.
+    #[arg(long, env = "CC_MAINTENANCE")]
+    maintenance: bool,
+
+    /// Message sent in maintenance mode.
+    #[arg(long, default_value = "down for maintenance", requires = "maintenance", env = "CC_MAINTENANCE_MESSAGE")]
+    maintenance_message: String,
+
+    /// Path to an html file to serve instead of the message in maintenance mode.
+    #[arg(long, requires = "maintenance", env = "CC_MAINTENANCE_PAGE")]
+    maintenance_page: Option<PathBuf>,
+
+    /// Send a `HEAD` request to new urls before storing them, rejecting ones that error or return 4xx/5xx.
+    #[arg(long, env = "CC_VALIDATE_TARGET_URL")]
+    validate_target_url: bool,
+
+    /// Timeout for `--validate-target-url` requests, in milliseconds.
+    #[arg(long, default_value_t = 3000, requires = "validate_target_url", env = "CC_VALIDATE_TIMEOUT_MS")]
+    validate_timeout_ms: u64,
+
+    /// Answer requests that take longer than this many seconds with 504.
+    #[arg(long, env = "CC_REQUEST_TIMEOUT")]
+    request_timeout: Option<u64>,
+
+    /// Answer `GET /{code}` with the url as json instead of redirecting. For api-only deployments.
+    #[arg(long, env = "CC_DISABLE_REDIRECT")]
+    disable_redirect: bool,
+
+    /// Answer unknown codes with an html 404 suggesting codes that differ by a character, for mistyped links.
+    /// Only the first 100000 codes are looked through.
+    #[arg(long, env = "CC_SUGGEST")]
+    suggest: bool,
+
+    /// Serve `/{code}/preview.png` social cards, with text in this TrueType/OpenType font.
+    #[arg(long, value_name = "FILE", env = "CC_PREVIEW_FONT")]
+    preview_font: Option<PathBuf>,
+
+    /// Answer `GET /{code}` with a page showing where the link goes, which redirects after this many seconds
+    /// (at most 30). 0 redirects straight away.
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u64).range(0..=MAX_PREVIEW_DELAY), env = "CC_PREVIEW_DELAY")]
+    preview_delay: u64,
+
+    /// Most `GET /{code}` requests answered per second, across all clients and databases, 0 for no limit.
+    /// Requests over the limit get a 429. Each code checked by `/admin/exists` counts as one.
+    #[arg(long, default_value_t = 0, env = "CC_MAX_REDIRECTS_PER_SEC")]
+    max_redirects_per_sec: u32,
+
+    /// Keep up to this many code -> url lookups in memory, 0 to disable.
+    #[arg(long, default_value_t = 0, env = "CC_CACHE_SIZE")]
+    cache_size: usize,
+
+    /// Seconds a cached lookup is used for before going back to the database.
+    #[arg(long, default_value_t = 60, env = "CC_CACHE_TTL")]
+    cache_ttl: u64,
+
+    /// How hard new codes are committed to disk. `none` skips the fsync on each commit, which is much
+    /// faster but can lose recently created codes if the machine crashes. `import-urls` and `merge` always fsync.
+    #[arg(long, value_enum, default_value_t = DurabilityLevel::Immediate, env = "CC_DURABILITY")]
+    durability: DurabilityLevel,
+
+    /// Most codes a single client ip can create, 0 for no limit. Behind a reverse proxy every client has the
+    /// proxy's ip, so this is only useful when clients connect directly.
+    #[arg(long, default_value_t = 0, env = "CC_MAX_CODES_PER_IP")]
+    max_codes_per_ip: u64,
+
+    /// Answer `/put` with 503 if the database write lock can't be taken within this many milliseconds.
+    #[arg(long, default_value_t = 5000, env = "CC_WRITE_TIMEOUT_MS")]
+    write_timeout_ms: u64,
+
+    /// Close and reopen a database after a burst of errors, for filesystems with transient failures (like NFS).
+    /// Requests get a 503 while it's closed.
+    #[arg(long, env = "CC_DB_REOPEN_ON_ERROR")]
+    db_reopen_on_error: bool,
+
+    /// Database errors within `--db-error-window-secs` it takes to reopen, with `--db-reopen-on-error`.
+    #[arg(long, default_value_t = 5, env = "CC_DB_ERROR_THRESHOLD")]
+    db_error_threshold: usize,
+
+    /// Seconds database errors are counted over, for `--db-error-threshold`.
+    #[arg(long, default_value_t = 10, env = "CC_DB_ERROR_WINDOW_SECS")]
+    db_error_window_secs: u64,
+
+    /// Milliseconds to wait between closing the database and opening it again (and between retries).
+    #[arg(long, default_value_t = 2000, env = "CC_DB_REOPEN_DELAY_MS")]
+    db_reopen_delay_ms: u64,
+
+    /// Log submitted urls that fail validation, with the query and fragment cut off.
+    /// For troubleshooting integrations, not for production.
+    #[arg(long, env = "CC_LOG_REJECTED_URLS")]
+    log_rejected_urls: bool,
+
+    /// Indent json responses by this many spaces, for reading them straight from curl. 0 keeps them compact.
+    #[arg(long, default_value_t = 0, env = "CC_JSON_RESPONSE_INDENT")]
+    json_response_indent: usize,
+
+    /// Length of the id sent in `X-Request-ID` with every response: 8, 16, 24, 32 or 64.
+    #[arg(long, default_value_t = 32, value_parser = parse_request_id_length, env = "CC_RESPONSE_ID_LENGTH")]
+    response_id_length: usize,
+
+    /// How request ids look. `uuid` is only available with a length of 32.
+    #[arg(long, value_enum, default_value_t = RequestIdFormat::Uuid, env = "CC_RESPONSE_ID_FORMAT")]
+    response_id_format: RequestIdFormat,
+
+    /// Send back the client's own `X-Request-ID`, if it's a uuid or hex, instead of making a new one.
+    /// Malformed ones are replaced (and logged).
+    #[arg(long, env = "CC_REQUEST_ID_PROPAGATE")]
+    request_id_propagate: bool,
+
+    /// Longest incoming `X-Request-ID` kept by `--request-id-propagate`.
+    #[arg(long, default_value_t = 64, requires = "request_id_propagate", env = "CC_REQUEST_ID_MAX_LENGTH")]
+    request_id_max_length: usize,
+
+    /// Log requests that take at least this many milliseconds, 0 to disable.
+    #[arg(long, default_value_t = 500, env = "CC_LOG_SLOW_REQUESTS_MS")]
+    log_slow_requests_ms: u64,
+
+    /// Log requests that take at least this many milliseconds as very slow, 0 to disable.
+    #[arg(long, default_value_t = 5000, env = "CC_LOG_VERY_SLOW_REQUESTS_MS")]
+    log_very_slow_requests_ms: u64,
+
+    /// Log the headers and the start of the request and response bodies of a sample of non-GET requests,
+    /// for debugging an incident. `Authorization` and `Cookie` are redacted, but bodies are logged as they are.
+    #[arg(long, env = "CC_LOG_SAMPLED_BODIES")]
+    log_sampled_bodies: bool,
+
+    /// Fraction of requests logged by `--log-sampled-bodies`, from 0 to 1.
+    #[arg(long, default_value_t = 1.0, value_parser = parse_sampling_rate, requires = "log_sampled_bodies", env = "CC_BODY_SAMPLING_RATE")]
+    body_sampling_rate: f64,
+
+    /// Send `X-Content-Type-Options`, `Referrer-Policy` and a restrictive `Content-Security-Policy`
+    /// with html pages.
+    #[arg(long, env = "CC_SECURITY_HEADERS")]
+    security_headers: bool,
+
+    /// Send `Server: cc/<version>` with every response.
+    #[arg(long, env = "CC_EXPOSE_VERSION_HEADER")]
+    expose_version_header: bool,
+
+    /// Send this as the `Server` header with every response.
+    #[arg(long, value_name = "VALUE", conflicts_with = "expose_version_header", env = "CC_SERVER_HEADER")]
+    server_header: Option<String>,
+
+    /// Redirect common malformed code paths, like `//abc` or `/abc.`, to the code they were meant to be.
+    #[arg(long, env = "CC_FIX_MALFORMED_CODES")]
+    fix_malformed_codes: bool,
+
+    /// Look up `/{code}/index.html` and the like as just `/{code}`, see `--strip-suffixes`.
+    #[arg(long, env = "CC_PATH_NORMALIZATION")]
+    path_normalization: bool,
+
+    /// Comma-separated suffixes removed by `--path-normalization`.
+    #[arg(
+        long, value_name = "LIST", value_delimiter = ',',
+        default_value = "/index.html,/index.php,/default.asp", requires = "path_normalization", env = "CC_STRIP_SUFFIXES"
+    )]
+    strip_suffixes: Vec<String>,
+
+    /// Check the configuration, files and databases, then exit instead of starting.
+    /// These checks always run before binding; this just stops there.
+    #[arg(long, env = "CC_STARTUP_CHECK")]
+    startup_check: bool,
+
+    /// Keep a copy of the main database's mappings in this SQLite file, for tools that read SQLite.
+    /// It's only written to, in the background, and fully synced at startup if it's empty.
+    #[arg(long, value_name = "FILE", env = "CC_SQLITE_COMPAT_EXPORT")]
+    sqlite_compat_export: Option<PathBuf>,
+
+    /// Run this rhai script against the main database before starting to listen.
+    /// It can call `create_code(url)`, `delete_code(code)` and `list_codes()`.
+    #[arg(long, value_name = "FILE", env = "CC_STARTUP_SCRIPT")]
+    startup_script: Option<PathBuf>,
+
+    /// Also serve another database under `/{NAME}/...`, with its own codes. Can be given multiple times.
+    #[arg(long, value_name = "NAME=PATH", value_parser = parse_namespace, env = "CC_NAMESPACE")]
+    namespace: Vec<(String, PathBuf)>,
+
+    /// Comma-separated tables that `/health` also requires to exist, besides cc's own.
+    #[arg(long, value_delimiter = ',', env = "CC_HEALTHCHECK_EXTRA_TABLES")]
+    healthcheck_extra_tables: Vec<String>,
+
+    /// Shell command to run on shutdown, after in-flight requests have finished.
+    /// `CC_SHUTDOWN_REASON` is set in its environment.
+    #[arg(long, env = "CC_SHUTDOWN_HOOK")]
+    shutdown_hook: Option<String>,
+
+    /// Seconds to wait for `--shutdown-hook` to finish before exiting anyway.
+    #[arg(long, default_value_t = 30, requires = "shutdown_hook", env = "CC_SHUTDOWN_HOOK_TIMEOUT_SECS")]
+    shutdown_hook_timeout_secs: u64,
+
+    /// Report panics and 5xx responses to Sentry at this DSN.
+    #[cfg(feature = "sentry")]
+    #[arg(long, value_name = "DSN", env = "CC_SENTRY_DSN")]
+    sentry_dsn: Option<String>,
+
+    /// Environment to tag Sentry events with.
+    #[cfg(feature = "sentry")]
+    #[arg(long, value_name = "ENV", requires = "sentry_dsn", env = "CC_SENTRY_ENVIRONMENT")]
+    sentry_environment: Option<String>,
+
+    /// Release to tag Sentry events with.
+    #[cfg(feature = "sentry")]
+    #[arg(long, value_name = "RELEASE", requires = "sentry_dsn", env = "CC_SENTRY_RELEASE")]
+    sentry_release: Option<String>,
+}
+
 #[derive(Debug, Clone, Parser)]
 enum Commands {
     /// Start the server.
     #[command(alias = "s")]
-    Serve {
-        /// Base URL to serve on.
-        #[arg(long, default_value = "127.0.0.1:8080")]
-        url: SocketAddr,
+    Serve(Box<ServeArgs>),
 
-        /// Path to an html file to serve on the root path.
-        #[arg(long)]
-        index: Option<PathBuf>,
-    },
     /// List all code -> url mappings in the database.
     #[command(name = "ls")]
-    List,
+    List {
+        /// Only list mappings with this tag.
+        #[arg(long)]
+        tag: Option<String>,
+    },
+
+    /// Replace the tags on a mapping. Giving no tags removes them.
+    Tag {
+        /// Code of the mapping to tag.
+        code: String,
+
+        /// Comma-separated tags.
+        #[arg(value_delimiter = ',')]
+        tags: Vec<String>,
+    },
 
     /// Remove a mapping by code or all mappings.
     #[command(name = "rm")]
@@ -58,261 +413,4154 @@ enum Commands {
         /// Remove all mappings.
         #[arg(long, conflicts_with = "code")]
         all: bool,
+
+        /// Delete outright instead of leaving a tombstone, so the code can be reused.
+        /// Also removes existing tombstones.
+        #[arg(long)]
+        hard_delete: bool,
+    },
+
+    /// Create mappings for a file of urls, one per line.
+    ImportUrls {
+        /// Path to the file of urls.
+        file: PathBuf,
+
+        /// Fixed string to prepend to every generated code.
+        #[arg(long, default_value = "")]
+        code_prefix: String,
+
+        /// How generated codes look.
+        #[arg(long, value_enum, default_value_t = CodeProfile::Base64)]
+        code_profile: CodeProfile,
+
+        /// File of codes (one per line) never to generate, like `serve --code-reserved`.
+        #[arg(long, value_name = "FILE")]
+        code_reserved: Option<PathBuf>,
+    },
+
+    /// Check every stored url against the current validation rules, without changing anything.
+    Audit {
+        /// Also flag urls with no path, like `serve --deny-empty-path-targets`.
+        #[arg(long)]
+        deny_empty_path_targets: bool,
+
+        /// Remove the mappings that fail, leaving tombstones like `rm`.
+        #[arg(long)]
+        delete: bool,
+    },
+
+    /// Copy every mapping from another database into this one, for combining separate instances.
+    Merge {
+        /// Path to the database to merge in.
+        source: PathBuf,
+
+        /// What to do with codes that already point somewhere else here.
+        #[arg(long, value_enum, default_value_t = OnConflict::Skip)]
+        on_conflict: OnConflict,
+
+        /// Fixed string to prepend to regenerated codes.
+        #[arg(long, default_value = "")]
+        code_prefix: String,
+
+        /// How regenerated codes look.
+        #[arg(long, value_enum, default_value_t = CodeProfile::Base64)]
+        code_profile: CodeProfile,
+
+        /// File of codes (one per line) never to generate, like `serve --code-reserved`.
+        #[arg(long, value_name = "FILE")]
+        code_reserved: Option<PathBuf>,
+    },
+
+    /// Find and fix entries that don't match up between the code -> url and url -> code tables,
+    /// like after a crash or manual edits.
+    Gc {
+        /// Only report what would be fixed.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Clear the url -> code table and rebuild it from the code -> url table, in one transaction.
+    /// Where several codes point to the same url, only one of them is kept in the rebuilt table.
+    RebuildReverse,
+
+    /// Merge mappings from a backup database into this one.
+    Restore {
+        /// Path to the backup database.
+        backup: PathBuf,
+
+        /// Replace mappings whose code already exists with a different url.
+        #[arg(long)]
+        overwrite: bool,
+    },
+
+    /// Show the top mappings by some measure.
+    Top {
+        /// What to rank mappings by.
+        #[arg(long, value_enum, default_value_t = TopBy::UrlLength)]
+        by: TopBy,
+
+        /// How many mappings to show.
+        #[arg(short, default_value_t = 10)]
+        n: usize,
+
+        /// Print as json.
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Measure insert throughput and lookup latency on this machine, using a throwaway database.
+    /// The database path is ignored.
+    Bench {
+        /// How many mappings to insert, each in its own transaction like `/put`.
+        #[arg(short, default_value_t = 10000)]
+        m: usize,
+
+        /// How many random lookups to make, each in its own read transaction like `GET /{code}`.
+        #[arg(short, default_value_t = 10000)]
+        n: usize,
+
+        /// How hard inserts are committed to disk.
+        #[arg(long, value_enum, default_value_t = DurabilityLevel::Immediate)]
+        durability: DurabilityLevel,
+
+        /// How generated codes look.
+        #[arg(long, value_enum, default_value_t = CodeProfile::Base64)]
+        code_profile: CodeProfile,
+    },
+
+    /// Print detailed build information.
+    Version,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum DurabilityLevel {
+    /// Every commit is on disk before `/put` answers.
+    Immediate,
+    /// Commits are only made durable along with a later durable one.
+    None,
+}
+
+impl From<DurabilityLevel> for redb::Durability {
+    fn from(level: DurabilityLevel) -> Self {
+        match level {
+            DurabilityLevel::Immediate => redb::Durability::Immediate,
+            DurabilityLevel::None => redb::Durability::None,
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum PutStatus {
+    /// 201 for new codes, 200 for existing ones.
+    Split,
+    /// Always 200.
+    Ok,
+    /// Always 201.
+    Created,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum RequestIdFormat {
+    /// Lowercase hex digits.
+    Hex,
+    /// A random (v4) uuid, like `0f8fad5b-d9cb-469f-a165-70867728950e`.
+    Uuid,
+    /// Digits and upper- and lowercase letters.
+    Base62,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OnConflict {
+    /// Leave the existing mapping and drop the incoming one.
+    Skip,
+    /// Give the incoming url a new code.
+    Regenerate,
+    /// Merge nothing if any code conflicts.
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum TopBy {
+    /// Oldest first. Only codes with a recorded creation time are included.
+    Age,
+    /// Longest url first.
+    UrlLength,
+}
+
+#[derive(Serialize)]
+struct TopEntry {
+    code: String,
+    url: String,
+    /// Url length, or creation time in milliseconds since the unix epoch.
+    value: u64,
+}
+
+/// Written to `--metrics-file`.
+#[derive(Serialize)]
+struct MetricsSnapshot {
+    uptime_seconds: u64,
+    databases: Vec<DatabaseMetrics>,
+}
+
+#[derive(Serialize)]
+struct DatabaseMetrics {
+    /// Empty for the main database.
+    namespace: String,
+    /// Missing if the database couldn't be read.
+    mappings: Option<u64>,
+    redirects: u64,
+    created: u64,
+    db_errors: u64,
+    /// Missing without `--cache-size`.
+    cache_hits: Option<u64>,
+    cache_misses: Option<u64>,
+}
+
+/// Public numbers for `/status`. Deliberately nothing about individual codes, clients or configuration.
+#[derive(Serialize)]
+struct StatusResponse {
+    mappings: u64,
+    uptime_secs: u64,
+    version: &'static str,
+}
+
 #[derive(Serialize)]
 struct Response {
     ok: bool,
     msg: String // either the code or an error message
 }
 
-const CODE_TO_URL: TableDefinition<&str, &str> = TableDefinition::new("c2u");
-const URL_TO_CODE: TableDefinition<&str, &str> = TableDefinition::new("u2c");
-const ALLOWED_SCHEMES: &[&str] = &["http", "https"];
+/// Successful response from `/put`.
+#[derive(Serialize)]
+struct PutResponse {
+    ok: bool,
+    msg: String, // the code
+    created: bool // false if the url already had a code
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// Response from `GET /{code}` with `--disable-redirect`.
+#[derive(Serialize)]
+struct CodeResponse {
+    ok: bool,
+    url: String,
+    code: String,
+}
 
-    match cli.command {
-        Commands::Serve { url, index } => serve(cli.db, url, index).await?,
-        Commands::List => list(cli.db)?,
-        Commands::Remove { code, all } => remove(cli.db, code, all)?,
-    }
+/// Response from `/admin/exists`.
+#[derive(Serialize)]
+struct ExistsResponse {
+    present: Vec<String>,
+    missing: Vec<String>,
+}
 
-    Ok(())
+#[derive(Deserialize)]
+struct PutQuery {
+    /// Only look up an existing code for the url, without creating one.
+    #[serde(default)]
+    check_only: bool,
+    /// Comma-separated tags for a newly created code.
+    tags: Option<String>,
+    /// Whether a newly created code redirects permanently, instead of the default.
+    permanent: Option<bool>,
 }
 
-fn list(path: PathBuf) -> Result<()> {
-    if !path.is_file() {
-        eprintln!("database file does not exist or is not a file: {}", path.display());
-        std::process::exit(1);
-    }
-    let db = Database::open(&path)?;
-    let rd = db.begin_read()?;
-    let rd_c2u = rd.open_table(CODE_TO_URL)?;
+#[derive(Deserialize)]
+struct IndexQuery {
+    /// Code that was just created, passed along when redirecting back to the index.
+    created: Option<String>,
+}
 
-    println!("{} mapping{} found in {}:",
-             rd_c2u.len()?, if rd_c2u.len()? == 1 { "" } else { "s" }, path.display());
-    rd_c2u.iter()?.for_each(|res| {
-        if let Ok((code, url)) = res {
-            println!("  {} -> {}", code.value(), url.value());
-        } else {
-            println!("  error reading mapping: {}", res.err().unwrap());
-        }
-    });
+/// Prepended to every table name, from `--table-prefix`. Set once at startup, before any table is opened.
+static TABLE_PREFIX: OnceLock<String> = OnceLock::new();
 
-    Ok(())
+/// A table definition named `TABLE_PREFIX` + `name`. redb wants a `&'static str`, so the name is leaked,
+/// but each table's definition is only made once.
+fn table<V: redb::Value + 'static>(name: &str) -> TableDefinition<'static, &'static str, V> {
+    let prefix = TABLE_PREFIX.get().map(String::as_str).unwrap_or("");
+    TableDefinition::new(Box::leak(format!("{}{}", prefix, name).into_boxed_str()))
 }
 
-fn remove(
-    path: PathBuf,
-    code: Option<String>,
-    all: bool
-) -> Result<()> {
-    if !path.is_file() {
-        eprintln!("database file does not exist or is not a file: {}", path.display());
-        std::process::exit(1);
-    }
-    let mut db = Database::open(&path)?;
-    let wr = db.begin_write()?;
-    let mut wr_c2u = wr.open_table(CODE_TO_URL)?;
-    let mut wr_u2c = wr.open_table(URL_TO_CODE)?;
+static CODE_TO_URL: LazyLock<TableDefinition<&str, &str>> = LazyLock::new(|| table("c2u"));
+static URL_TO_CODE: LazyLock<TableDefinition<&str, &str>> = LazyLock::new(|| table("u2c"));
+/// Urls as they were submitted, before normalization. Only has entries for codes created with
+/// `--keep-original` whose url was changed by normalization.
+static ORIGINAL_URL: LazyLock<TableDefinition<&str, &str>> = LazyLock::new(|| table("c2o"));
+/// When each code was created, in milliseconds since the unix epoch.
+/// Codes created before this table existed don't have an entry.
+static CREATED: LazyLock<TableDefinition<&str, u64>> = LazyLock::new(|| table("c2t"));
+/// Comma-separated tags for each code. Codes without tags don't have an entry.
+static TAGS: LazyLock<TableDefinition<&str, &str>> = LazyLock::new(|| table("tags"));
+/// Whether each code redirects permanently (308) or temporarily (307), if it was given at creation.
+/// Codes without an entry redirect permanently.
+static STATUS: LazyLock<TableDefinition<&str, bool>> = LazyLock::new(|| table("status"));
+/// The ip that created each code, with `--max-codes-per-ip`.
+static CODE_TO_CREATOR_IP: LazyLock<TableDefinition<&str, &str>> = LazyLock::new(|| table("c2ip"));
+/// How many existing codes each ip has created, with `--max-codes-per-ip`.
+static CREATOR_IP_TO_COUNT: LazyLock<TableDefinition<&str, u64>> = LazyLock::new(|| table("ip2n"));
+/// Tombstones for removed codes, with the url they used to point to. Codes here are never reused.
+static DELETED: LazyLock<TableDefinition<&str, &str>> = LazyLock::new(|| table("deleted"));
+const ALLOWED_SCHEMES: &[&str] = &["http", "https"];
+/// Always refused, even if they end up in `ALLOWED_SCHEMES`, since browsers run or open them locally.
+const DENY_SCHEMES: &[&str] = &["javascript", "data", "vbscript", "file"];
+const MAX_CODE_PREFIX_LEN: usize = 16;
+const MAX_CODE_LEN: usize = 64;
+/// Codes (and namespace names) that would be shadowed by other routes.
+const RESERVED_CODES: &[&str] = &["put", "health", "admin", "status"];
+/// Cache hits take well under a millisecond and database lookups a few, finer than Prometheus' usual buckets.
+const DEFAULT_LATENCY_BUCKETS: &str = "0.0001,0.0005,0.001,0.0025,0.005,0.01,0.05,0.1,0.5,1";
+/// How often the mapping count on `/status` is refreshed.
+const STATUS_REFRESH: Duration = Duration::from_secs(60);
+/// Most codes `/admin/exists` checks at once.
+const MAX_EXISTS_CODES: usize = 1000;
+/// The example frontend, served with `--ui`.
+const BUILTIN_UI: &str = include_str!("example_index.html");
+const GONE_PAGE: &str = "<!DOCTYPE html><html lang=en><head><meta charset=UTF-8><title>cc</title></head>\
+    <body><p>this link was removed.</p></body></html>";
+const MAX_LOGGED_URL_LEN: usize = 200;
+/// Bytes of each body logged by `--log-sampled-bodies`.
+const MAX_LOGGED_BODY_LEN: usize = 512;
+/// Largest request body `--log-sampled-bodies` reads in, the same as axum's default limit for extractors.
+const MAX_SAMPLED_BODY_LEN: usize = 2 * 1024 * 1024;
+const MAX_TAGS: usize = 10;
+const MAX_PREVIEW_CARDS: usize = 1000;
+/// Codes looked through for `--suggest`, so a miss costs the same however big the database gets.
+const MAX_SUGGEST_SCAN: usize = 100_000;
+const MAX_SUGGESTIONS: usize = 5;
+/// Longest `--preview-delay`, in seconds.
+const MAX_PREVIEW_DELAY: u64 = 30;
+/// Mappings written per transaction by `merge`.
+const MERGE_BATCH_SIZE: usize = 1000;
+const MAX_TAG_LEN: usize = 32;
+const X_ROBOTS_TAG: HeaderName = HeaderName::from_static("x-robots-tag");
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+/// Sent with html pages under `--security-headers`. The built-in ui needs its inline script and style.
+const HTML_CSP: &str = "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; \
+    connect-src 'self'; img-src 'self'; form-action 'self'; base-uri 'none'; frame-ancestors 'none'";
 
-    if all {
-        let count = wr_c2u.len()?;
-        wr_c2u.retain(|_, _| false)?;
-        wr_u2c.retain(|_, _| false)?;
-        println!("removed {} mapping{}", count, if count == 1 { "" } else { "s" });
-    } else {
-        let code = code.unwrap();
-        let url = match wr_c2u.get(code.as_str())? {
-            Some(url) => url.value().to_string(),
-            None => {
-                println!("code not found: {}", code);
-                return Ok(());
-            }
-        };
-        wr_c2u.remove(code.as_str())?;
-        wr_u2c.remove(url.as_str())?;
-        println!("removed mapping {} -> {}", code, url);
-    }
+/// How generated codes look.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum CodeProfile {
+    /// 6 base64url characters, like `xK3-_q` (32 bits).
+    Base64,
+    /// 5 consonant-vowel syllables, like `bakodimure` (~31.6 bits).
+    /// Easier to read aloud, but at ~3.2 bits per character instead of 6, so nearly twice as long.
+    Pronounceable,
+}
 
-    drop(wr_c2u);
-    drop(wr_u2c);
+// no c/q/w/x/y, which are easy to mishear or misspell
+const CONSONANTS: &[u8] = b"bdfghjklmnprstvz";
+const VOWELS: &[u8] = b"aeiou";
+/// 16 * 5 = 80 possibilities (~6.3 bits) per syllable, so 5 syllables gets close to the 32 bits of base64 codes.
+const PRONOUNCEABLE_SYLLABLES: usize = 5;
 
-    wr.commit()?;
-    db.compact()?;
+/// The request's X-Request-ID, put in the request's extensions before it reaches a handler.
+#[derive(Clone)]
+struct RequestId(String);
 
-    Ok(())
+struct AppState {
+    /// Empty while `--db-reopen-on-error` is reopening it.
+    db: ArcSwapOption<Database>,
+    reopen: Option<DbReopen>,
+    /// Gets every new or removed mapping, with `--sqlite-compat-export`.
+    mirror: Option<SqliteMirror>,
+    /// Prepended to every generated code.
+    code_prefix: String,
+    code_profile: CodeProfile,
+    /// Codes never to generate, lowercased, from `--code-reserved`. Shared by all databases.
+    reserved: Arc<RwLock<HashSet<String>>>,
+    /// Generates codes instead of the thread rng, with `--seed`. Shared by all databases.
+    rng: Option<Arc<Mutex<StdRng>>>,
+    keep_original: bool,
+    /// Store urls as submitted instead of normalized, with `--no-double-slash-normalize`.
+    raw_urls: bool,
+    put_status: PutStatus,
+    /// Where this server is reached, with `--base-url`.
+    base_url: Option<url::Url>,
+    /// Hosts and ports that reach this server through the address it's bound to, which new urls can't point at.
+    self_hosts: HashSet<(String, u16)>,
+    /// Most short links followed looking for redirect cycles.
+    redirect_loop_max: usize,
+    /// Relative urls are resolved against this, with `--relative-base`.
+    relative_base: Option<url::Url>,
+    deny_empty_path_targets: bool,
+    validate_utf8_strict: bool,
+    /// Used to check targets respond before storing them, with `--validate-target-url`.
+    target_client: Option<reqwest::Client>,
+    log_rejected_urls: bool,
+    /// Send urls as json instead of redirecting.
+    disable_redirect: bool,
+    /// Suggest similar codes on a 404, with `--suggest`.
+    suggest: bool,
+    /// Seconds the interstitial page waits before redirecting, 0 to redirect straight away.
+    preview_delay: u64,
+    /// Font and rendered images for `/{code}/preview.png`, with `--preview-font`.
+    preview: Option<PreviewCards>,
+    /// Shared by every database, with `--max-redirects-per-sec`.
+    redirect_limit: Option<Arc<Mutex<TokenBucket>>>,
+    /// Recent lookups for `get_code`, with `--cache-size`.
+    cache: Option<RedirectCache>,
+    /// Durability of `/put` and startup script commits.
+    durability: DurabilityLevel,
+    /// Codes each ip can create, 0 for no limit.
+    max_codes_per_ip: u64,
+    /// How long `/put` waits for the write lock.
+    write_timeout: Duration,
+    /// Extra tables `/health` checks for.
+    health_tables: Vec<String>,
+    /// Result of the last background database check.
+    db_healthy: AtomicBool,
+    /// Codes found by `get_code` since startup, for the SIGUSR1 stats dump.
+    redirects: AtomicU64,
+    /// Codes created by `/put` since startup.
+    created: AtomicU64,
+    /// Mapping count for `/status`, refreshed every `STATUS_REFRESH`.
+    status_mappings: AtomicU64,
+    started: Instant,
+    /// Database errors answered with a 500 since startup.
+    db_errors: AtomicU64,
 }
 
-async fn serve(
-    path: PathBuf,
-    url: SocketAddr,
-    index: Option<PathBuf>
-) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)?;
+impl AppState {
+    /// The database, unless it's closed for a reopen.
+    fn db(&self) -> Result<Arc<Database>> {
+        self.db.load_full().ok_or_else(|| anyhow::anyhow!("database is being reopened"))
     }
-    let db = Arc::new(Database::create(&path)?);
 
-    if !db.begin_read()?.list_tables()?.any(|tb| tb.name() == CODE_TO_URL.name()) {
-        let wr = db.begin_write()?;
-        wr.open_table(CODE_TO_URL)?;
-        wr.open_table(URL_TO_CODE)?;
-        wr.commit()?;
+    /// Counts a database error in a handler, reopening the database if there have been too many lately.
+    fn db_error(self: &Arc<Self>) {
+        self.db_errors.fetch_add(1, Ordering::Relaxed);
+        let Some(reopen) = &self.reopen else { return };
+        {
+            let mut errors = reopen.errors.lock().unwrap();
+            let now = Instant::now();
+            errors.push_back(now);
+            while errors.front().is_some_and(|t| now.duration_since(*t) > reopen.window) {
+                errors.pop_front();
+            }
+            if errors.len() <= reopen.threshold {
+                return;
+            }
+        }
+
+        if !reopen.reopening.swap(true, Ordering::Relaxed) {
+            tokio::spawn(reopen_db(self.clone()));
+        }
     }
+}
 
-    println!("Starting cc at http://{}, db at {}", url, path.display());
+/// Settings and recent errors for `--db-reopen-on-error`.
+struct DbReopen {
+    path: PathBuf,
+    threshold: usize,
+    window: Duration,
+    delay: Duration,
+    /// When recent database errors happened, oldest first.
+    errors: Mutex<VecDeque<Instant>>,
+    reopening: AtomicBool,
+}
 
-    let mut app = Router::new()
-        .route("/put", post(put_new))
-        .route("/{code}", get(get_code))
-        .with_state(db);
+/// Closes the database and opens it again, retrying until it works.
+async fn reopen_db(state: Arc<AppState>) {
+    let Some(reopen) = &state.reopen else { return };
+    println!("too many database errors, reopening {}", reopen.path.display());
 
-    if let Some(index) = &index {
-        if !index.is_file() {
-            eprintln!("index file does not exist or is not a file: {}", index.display());
-            std::process::exit(1);
+    // requests already holding the old database keep it open until they finish,
+    // and redb refuses to open it twice, so this just retries until they have
+    state.db.store(None);
+    loop {
+        tokio::time::sleep(reopen.delay).await;
+        let path = reopen.path.clone();
+        match tokio::task::spawn_blocking(move || open_db(&path)).await {
+            Ok(Ok(db)) => {
+                state.db.store(Some(Arc::new(db)));
+                break;
+            }
+            Ok(Err(e)) => println!("reopening {} failed: {}", reopen.path.display(), e),
+            Err(e) => println!("reopening {} failed: {}", reopen.path.display(), e),
         }
-
-        let index = Html(fs::read_to_string(index)?);
-        app = app.route("/", get(move || async { index }));
     }
 
-    app = app.fallback_service(get(|| async { StatusCode::NOT_FOUND }));
+    reopen.errors.lock().unwrap().clear();
+    reopen.reopening.store(false, Ordering::Relaxed);
+    println!("reopened {}", reopen.path.display());
+}
 
-    let listener = TcpListener::bind(url).await?;
-    axum::serve(listener, app).await?;
+/// Bucket bounds for `--latency-histogram-buckets`. A newtype so clap doesn't take it as a list of values.
+#[derive(Debug, Clone)]
+struct LatencyBuckets(Vec<f64>);
 
-    Ok(())
+/// Request durations for `--metrics-port`, as a Prometheus histogram. Shared by all databases.
+struct LatencyHistogram {
+    name: &'static str,
+    help: &'static str,
+    buckets: Vec<f64>,
+    /// Requests in each bucket (not cumulative), and one more for those over the last bound.
+    counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
 }
 
-macro_rules! nope {
-    ($e:expr) => {
-        {
-            println!("db error: {}", $e);
-            let j = Json(Response { ok: false, msg: "problem with database".to_string() });
-            return (StatusCode::INTERNAL_SERVER_ERROR, j).into_response();
+impl LatencyHistogram {
+    fn new(name: &'static str, help: &'static str, buckets: &LatencyBuckets) -> Self {
+        Self {
+            name,
+            help,
+            buckets: buckets.0.clone(),
+            counts: (0..=buckets.0.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
         }
-    };
-}
-
-async fn get_code(State(db): State<Arc<Database>>, code: Path<String>) -> AxumResponse {
-    let rd = match db.begin_read() {
-        Ok(rd) => rd,
-        Err(e) => nope!(e),
-    };
+    }
 
-    let rd_c2u = match rd.open_table(CODE_TO_URL) {
-        Ok(tb) => tb,
-        Err(e) => nope!(e)
-    };
+    fn observe(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let i = self.buckets.iter().position(|&le| secs <= le).unwrap_or(self.buckets.len());
+        self.counts[i].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
 
-    return match rd_c2u.get(code.as_str()) {
-        Ok(Some(url)) => {
-            println!("found code {} -> {}", code.as_str(), url.value());
-            Redirect::permanent(url.value()).into_response()
-        },
-        Ok(None) => StatusCode::NOT_FOUND.into_response(),
-        Err(e) => nope!(e)
+    fn render(&self) -> String {
+        let mut out = format!("# HELP {0} {1}\n# TYPE {0} histogram\n", self.name, self.help);
+        let mut total = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            total += count.load(Ordering::Relaxed);
+            let le = self.buckets.get(i).map_or("+Inf".to_string(), |le| le.to_string());
+            out += &format!("{}_bucket{{le=\"{}\"}} {}\n", self.name, le, total);
+        }
+        out += &format!("{}_sum {}\n", self.name, self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6);
+        out += &format!("{}_count {}\n", self.name, total);
+        out
     }
 }
 
-async fn put_new(State(db): State<Arc<Database>>, raw_url: Bytes) -> AxumResponse {
-    let mut str_url = match std::str::from_utf8(&raw_url) {
-        Ok(u) => u.trim().to_string(),
-        Err(e) => {
-            let j = Json(Response { ok: false, msg: format!("invalid utf-8 in url: {}", e) }).into_response();
-            return (StatusCode::BAD_REQUEST, j).into_response();
-        }
-    };
+/// Allows `rate` requests a second, with bursts of up to a second's worth.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
 
-    let url: Uri = match str_url.parse() {
-        Ok(u) => u,
-        Err(e) => {
-            let j = Json(Response { ok: false, msg: format!("invalid url: {}", e) }).into_response();
-            return (StatusCode::BAD_REQUEST, j).into_response();
-        }
-    };
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        Self { rate: rate as f64, tokens: rate as f64, updated: Instant::now() }
+    }
 
-    // normalize the url
-    if str_url.contains("#") {
-        str_url = url.to_string() + "#" + str_url.splitn(2, "#").nth(1).unwrap_or("");
-    } else {
-        str_url = url.to_string();
+    /// Takes a token, or says how long until there is one.
+    fn take(&mut self) -> Result<(), Duration> {
+        self.take_n(1)
     }
 
-    if let Some(scheme) = url.scheme_str() {
-        if !ALLOWED_SCHEMES.contains(&scheme) {
-            let j = Json(Response { ok: false, msg: format!("unsupported url scheme: {}", scheme) }).into_response();
-            return (StatusCode::BAD_REQUEST, j).into_response();
+    /// Takes `n` tokens once there's at least one, going into debt for the rest, or says how long until there is one.
+    /// This way a batch bigger than the bucket still gets through, but holds up whatever comes after it.
+    fn take_n(&mut self, n: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.updated).as_secs_f64() * self.rate).min(self.rate);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= n as f64;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
         }
-    } else {
-        let j = Json(Response { ok: false, msg: "url missing scheme".to_string() }).into_response();
-        return (StatusCode::BAD_REQUEST, j).into_response();
+    }
+}
+
+/// Code -> url lookups kept in memory for a while, so hot links don't each open a read transaction.
+/// Only mappings that exist are cached, and mappings are never changed while the server holds the database,
+/// so the ttl just bounds how stale an entry can get.
+struct RedirectCache {
+    entries: Mutex<CacheEntries>,
+    size: usize,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    /// Url, per-code status, when it was added and when it was last used, by code.
+    by_code: HashMap<String, (String, Option<bool>, Instant, u64)>,
+    /// Codes by when they were last used, so the least recently used one is first.
+    by_use: BTreeMap<u64, String>,
+    /// Counts up on every use.
+    tick: u64,
+}
+
+impl CacheEntries {
+    /// Marks `code` as just used, returning the tick to store with it.
+    fn touch(&mut self, code: &str, last: Option<u64>) -> u64 {
+        if let Some(last) = last {
+            self.by_use.remove(&last);
+        }
+        self.tick += 1;
+        self.by_use.insert(self.tick, code.to_string());
+        self.tick
+    }
+
+    fn remove(&mut self, code: &str) {
+        if let Some((_, _, _, last)) = self.by_code.remove(code) {
+            self.by_use.remove(&last);
+        }
+    }
+}
+
+impl RedirectCache {
+    fn new(size: usize, ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(CacheEntries { by_code: HashMap::with_capacity(size), ..Default::default() }),
+            size,
+            ttl,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self, code: &str) -> Option<(String, Option<bool>)> {
+        let mut entries = self.entries.lock().unwrap();
+        let url = match entries.by_code.get(code) {
+            Some(&(ref url, permanent, added, last)) if added.elapsed() < self.ttl => {
+                let url = url.clone();
+                let tick = entries.touch(code, Some(last));
+                if let Some(entry) = entries.by_code.get_mut(code) {
+                    entry.3 = tick;
+                }
+                Some((url, permanent))
+            }
+            Some(_) => {
+                entries.remove(code);
+                None
+            }
+            None => None,
+        };
+
+        let counter = if url.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        url
+    }
+
+    fn insert(&self, code: &str, url: &str, permanent: Option<bool>) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(code);
+        // full, make room by dropping the least recently used
+        if entries.by_code.len() >= self.size && let Some((_, lru)) = entries.by_use.pop_first() {
+            entries.by_code.remove(&lru);
+        }
+        let tick = entries.touch(code, None);
+        entries.by_code.insert(code.to_string(), (url.to_string(), permanent, Instant::now(), tick));
+    }
+}
+
+/// A write-only SQLite copy of the mappings. Changes are sent to a blocking task that applies them in order,
+/// so a slow disk never holds up a request, and the copy may lag behind a little.
+struct SqliteMirror {
+    changes: tokio::sync::mpsc::UnboundedSender<(String, Option<String>)>,
+}
+
+impl SqliteMirror {
+    /// Opens (or creates) the file, brings it up to date with the database, and starts the writer.
+    /// Catching up at startup covers whatever subcommands (`rm`, `merge`, `restore`, `gc`...) changed while the server was down.
+    fn start(path: &std::path::Path, db: &Database) -> Result<Self> {
+        let mut conn = rusqlite::Connection::open(path)?;
+        conn.execute("CREATE TABLE IF NOT EXISTS mappings (code TEXT PRIMARY KEY, url TEXT NOT NULL)", ())?;
+
+        let rd = db.begin_read()?;
+        let rd_c2u = rd.open_table(*CODE_TO_URL)?;
+        let tx = conn.transaction()?;
+        let mirrored = {
+            let mut stmt = tx.prepare("SELECT code FROM mappings")?;
+            stmt.query_map((), |row| row.get::<_, String>(0))?.collect::<Result<Vec<_>, _>>()?
+        };
+
+        let mut removed = 0;
+        for code in mirrored {
+            if rd_c2u.get(code.as_str())?.is_none() {
+                removed += tx.execute("DELETE FROM mappings WHERE code = ?1", (&code,))?;
+            }
+        }
+
+        let mut updated = 0;
+        for res in rd_c2u.iter()? {
+            let (code, url) = res?;
+            updated += tx.execute(
+                "INSERT INTO mappings (code, url) VALUES (?1, ?2) \
+                 ON CONFLICT (code) DO UPDATE SET url = excluded.url WHERE url != excluded.url",
+                (code.value(), url.value()),
+            )?;
+        }
+        tx.commit()?;
+        if updated > 0 || removed > 0 {
+            println!("synced {}: {} mappings added or changed, {} removed", path.display(), updated, removed);
+        }
+
+        let (changes, mut rx) = tokio::sync::mpsc::unbounded_channel::<(String, Option<String>)>();
+        let path = path.display().to_string();
+        tokio::task::spawn_blocking(move || {
+            while let Some((code, url)) = rx.blocking_recv() {
+                let res = match &url {
+                    Some(url) => conn.execute("INSERT OR REPLACE INTO mappings (code, url) VALUES (?1, ?2)", (&code, url)),
+                    None => conn.execute("DELETE FROM mappings WHERE code = ?1", (&code,)),
+                };
+                if let Err(e) = res {
+                    println!("couldn't update {} for {}: {}", path, code, e);
+                }
+            }
+        });
+
+        Ok(Self { changes })
+    }
+
+    fn stored(&self, code: &str, url: &str) {
+        let _ = self.changes.send((code.to_string(), Some(url.to_string())));
+    }
+
+    fn removed(&self, code: &str) {
+        let _ = self.changes.send((code.to_string(), None));
+    }
+}
+
+/// Social card images, so pasting a short url into a chat app shows something.
+struct PreviewCards {
+    font: ab_glyph::FontArc,
+    /// Rendered pngs by code. Cleared when it gets to `MAX_PREVIEW_CARDS`.
+    rendered: Mutex<HashMap<String, Bytes>>,
+}
+
+impl AppState {
+    fn new_code(&self) -> String {
+        let reserved = self.reserved.read().unwrap();
+        match &self.rng {
+            Some(rng) => new_code(&self.code_prefix, self.code_profile, &reserved, &mut *rng.lock().unwrap()),
+            None => new_code(&self.code_prefix, self.code_profile, &reserved, &mut rand::rng()),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    TABLE_PREFIX.set(cli.table_prefix.clone()).unwrap();
+
+    if let Commands::Version = cli.command {
+        version();
+        return Ok(());
+    }
+
+    // doesn't touch the given database, so doesn't need one
+    if let Commands::Bench { m, n, durability, code_profile } = cli.command {
+        return bench(m, n, durability, code_profile);
+    }
+
+    let db = match (cli.db, cli.db_path_env) {
+        (Some(db), _) => db,
+        (None, Some(var)) => match std::env::var_os(&var) {
+            Some(db) => PathBuf::from(db),
+            None => {
+                eprintln!("environment variable {} is not set", var);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => {
+            eprintln!("no database given, pass a path or --db-path-env");
+            std::process::exit(2);
+        }
+    };
+
+    match cli.command {
+        Commands::Serve(args) => serve(db, *args).await?,
+        Commands::List { tag } => list(db, tag)?,
+        Commands::Tag { code, tags } => tag(db, code, tags)?,
+        Commands::Remove { code, all, hard_delete } => remove(db, code, all, hard_delete)?,
+        Commands::ImportUrls { file, code_prefix, code_profile, code_reserved } => {
+            import_urls(db, file, code_prefix, code_profile, code_reserved)?
+        }
+        Commands::Audit { deny_empty_path_targets, delete } => audit(db, deny_empty_path_targets, delete)?,
+        Commands::Merge { source, on_conflict, code_prefix, code_profile, code_reserved } => {
+            merge(db, source, on_conflict, code_prefix, code_profile, code_reserved)?
+        }
+        Commands::Gc { dry_run } => gc(db, dry_run)?,
+        Commands::RebuildReverse => rebuild_reverse(db)?,
+        Commands::Restore { backup, overwrite } => restore(db, backup, overwrite)?,
+        Commands::Top { by, n, json } => top(db, by, n, json)?,
+        Commands::Version | Commands::Bench { .. } => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn version() {
+    let built = env!("BUILD_TIMESTAMP").parse().map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+    println!("cc {} ({})", env!("CARGO_PKG_VERSION"), env!("GIT_HASH"));
+    println!("built:  {}", built.map(httpdate::fmt_http_date).unwrap_or_else(|_| "unknown".to_string()));
+    println!("rustc:  {}", env!("RUSTC_VERSION"));
+    println!("target: {}", env!("TARGET"));
+    println!("redb:   {}", env!("REDB_VERSION"));
+    println!("axum:   {}", env!("AXUM_VERSION"));
+}
+
+fn list(path: PathBuf, tag: Option<String>) -> Result<()> {
+    if !path.is_file() {
+        eprintln!("database file does not exist or is not a file: {}", path.display());
+        std::process::exit(1);
+    }
+    let db = Database::open(&path)?;
+    let rd = db.begin_read()?;
+    let rd_c2u = rd.open_table(*CODE_TO_URL)?;
+    let rd_tags = open_optional(&rd, *TAGS)?;
+
+    let tags_of = |code: &str| -> Result<Option<String>> {
+        Ok(match &rd_tags {
+            Some(tb) => tb.get(code)?.map(|t| t.value().to_string()),
+            None => None,
+        })
+    };
+
+    let Some(tag) = tag else {
+        println!("{} mapping{} found in {}:",
+                 rd_c2u.len()?, if rd_c2u.len()? == 1 { "" } else { "s" }, path.display());
+        rd_c2u.iter()?.for_each(|res| {
+            if let Ok((code, url)) = res {
+                match tags_of(code.value()) {
+                    Ok(Some(tags)) => println!("  {} -> {} [{}]", code.value(), url.value(), tags),
+                    _ => println!("  {} -> {}", code.value(), url.value()),
+                }
+            } else {
+                println!("  error reading mapping: {}", res.err().unwrap());
+            }
+        });
+        return Ok(());
+    };
+
+    let mut tagged = Vec::new();
+    if let Some(rd_tags) = &rd_tags {
+        for res in rd_tags.iter()? {
+            let (code, tags) = res?;
+            if tags.value().split(',').any(|t| t == tag) && let Some(url) = rd_c2u.get(code.value())? {
+                tagged.push((code.value().to_string(), url.value().to_string(), tags.value().to_string()));
+            }
+        }
+    }
+
+    println!("{} mapping{} tagged {} in {}:",
+             tagged.len(), if tagged.len() == 1 { "" } else { "s" }, tag, path.display());
+    for (code, url, tags) in tagged {
+        println!("  {} -> {} [{}]", code, url, tags);
+    }
+
+    Ok(())
+}
+
+fn tag(path: PathBuf, code: String, tags: Vec<String>) -> Result<()> {
+    if !path.is_file() {
+        eprintln!("database file does not exist or is not a file: {}", path.display());
+        std::process::exit(1);
+    }
+    let tags = match parse_tags(&tags.join(",")) {
+        Ok(tags) => tags,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            std::process::exit(1);
+        }
+    };
+
+    let db = open_db(&path)?;
+    let wr = db.begin_write()?;
+    let wr_c2u = wr.open_table(*CODE_TO_URL)?;
+    if wr_c2u.get(code.as_str())?.is_none() {
+        println!("code not found: {}", code);
+        return Ok(());
+    }
+
+    let mut wr_tags = wr.open_table(*TAGS)?;
+    if tags.is_empty() {
+        wr_tags.remove(code.as_str())?;
+        println!("removed tags from {}", code);
+    } else {
+        wr_tags.insert(code.as_str(), tags.join(",").as_str())?;
+        println!("tagged {}: {}", code, tags.join(","));
+    }
+
+    drop(wr_c2u);
+    drop(wr_tags);
+    wr.commit()?;
+
+    Ok(())
+}
+
+fn top(path: PathBuf, by: TopBy, n: usize, json: bool) -> Result<()> {
+    if !path.is_file() {
+        eprintln!("database file does not exist or is not a file: {}", path.display());
+        std::process::exit(1);
+    }
+    let db = Database::open(&path)?;
+    let rd = db.begin_read()?;
+    let rd_c2u = rd.open_table(*CODE_TO_URL)?;
+
+    // keep only the n best seen so far, so memory doesn't grow with the database
+    // (scores are flipped for age so the oldest rank highest)
+    let mut heap = BinaryHeap::with_capacity(n + 1);
+    let mut keep = |score: u64, code: &str| {
+        heap.push(Reverse((score, code.to_string())));
+        if heap.len() > n {
+            heap.pop();
+        }
+    };
+
+    match by {
+        TopBy::UrlLength => {
+            for res in rd_c2u.iter()? {
+                let (code, url) = res?;
+                keep(url.value().len() as u64, code.value());
+            }
+        }
+        TopBy::Age => {
+            if let Some(rd_c2t) = open_optional(&rd, *CREATED)? {
+                for res in rd_c2t.iter()? {
+                    let (code, created) = res?;
+                    keep(u64::MAX - created.value(), code.value());
+                }
+            }
+        }
+    }
+
+    let mut entries = Vec::new();
+    for Reverse((score, code)) in heap.into_sorted_vec() {
+        let Some(url) = rd_c2u.get(code.as_str())?.map(|u| u.value().to_string()) else {
+            continue;
+        };
+        let value = match by {
+            TopBy::UrlLength => score,
+            TopBy::Age => u64::MAX - score,
+        };
+        entries.push(TopEntry { code, url, value });
+    }
+
+    if json {
+        println!("{}", serde_json::to_string(&entries)?);
+        return Ok(());
+    }
+
+    println!("top {} by {} in {}:", entries.len(), match by {
+        TopBy::UrlLength => "url length",
+        TopBy::Age => "age",
+    }, path.display());
+    for entry in entries {
+        let value = match by {
+            TopBy::UrlLength => entry.value.to_string(),
+            TopBy::Age => httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_millis(entry.value)),
+        };
+        println!("  {} -> {} ({})", entry.code, entry.url, value);
+    }
+
+    Ok(())
+}
+
+fn bench(m: usize, n: usize, durability: DurabilityLevel, code_profile: CodeProfile) -> Result<()> {
+    let path = std::env::temp_dir().join(format!("cc-bench-{}.redb", std::process::id()));
+    let result = run_bench(&path, m, n, durability, code_profile);
+    if let Err(e) = fs::remove_file(&path) {
+        println!("couldn't remove {}: {}", path.display(), e);
+    }
+    result
+}
+
+fn run_bench(
+    path: &std::path::Path,
+    m: usize,
+    n: usize,
+    durability: DurabilityLevel,
+    code_profile: CodeProfile,
+) -> Result<()> {
+    let db = Database::create(path)?;
+    init_tables(&db)?;
+    println!("benchmarking in {} ({:?} durability, {:?} codes)", path.display(), durability, code_profile);
+
+    let mut codes = Vec::with_capacity(m);
+    let start = Instant::now();
+    for i in 0..m {
+        let code = gen_key(code_profile, &mut rand::rng());
+        let url = format!("https://example.com/bench/{}/{}", i, code);
+
+        let mut wr = db.begin_write()?;
+        wr.set_durability(durability.into())?;
+        {
+            let mut wr_c2u = wr.open_table(*CODE_TO_URL)?;
+            let mut wr_u2c = wr.open_table(*URL_TO_CODE)?;
+            // a repeated code just overwrites, which costs about the same as an insert
+            wr_c2u.insert(code.as_str(), url.as_str())?;
+            wr_u2c.insert(url.as_str(), code.as_str())?;
+        }
+        wr.commit()?;
+        codes.push(code);
+    }
+    let elapsed = start.elapsed();
+    println!("inserted {} mappings in {:.2}s ({:.0}/s)", m, elapsed.as_secs_f64(), m as f64 / elapsed.as_secs_f64());
+
+    if codes.is_empty() || n == 0 {
+        return Ok(());
+    }
+
+    let mut rng = rand::rng();
+    let mut latencies = Vec::with_capacity(n);
+    for _ in 0..n {
+        let code = &codes[rng.random_range(0..codes.len())];
+        let start = Instant::now();
+        let rd = db.begin_read()?;
+        let rd_c2u = rd.open_table(*CODE_TO_URL)?;
+        rd_c2u.get(code.as_str())?;
+        latencies.push(start.elapsed());
+    }
+
+    latencies.sort();
+    let percentile = |p: usize| latencies[(latencies.len() * p / 100).min(latencies.len() - 1)];
+    println!(
+        "looked up {} codes: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        n, percentile(50), percentile(90), percentile(99), latencies[latencies.len() - 1],
+    );
+
+    Ok(())
+}
+
+fn remove(
+    path: PathBuf,
+    code: Option<String>,
+    all: bool,
+    hard_delete: bool
+) -> Result<()> {
+    if !path.is_file() {
+        eprintln!("database file does not exist or is not a file: {}", path.display());
+        std::process::exit(1);
+    }
+    let mut db = Database::open(&path)?;
+    let wr = db.begin_write()?;
+    let mut wr_c2u = wr.open_table(*CODE_TO_URL)?;
+    let mut wr_u2c = wr.open_table(*URL_TO_CODE)?;
+    let mut wr_c2o = wr.open_table(*ORIGINAL_URL)?;
+    let mut wr_c2t = wr.open_table(*CREATED)?;
+    let mut wr_tags = wr.open_table(*TAGS)?;
+    let mut wr_status = wr.open_table(*STATUS)?;
+    let mut wr_c2ip = wr.open_table(*CODE_TO_CREATOR_IP)?;
+    let mut wr_ip2n = wr.open_table(*CREATOR_IP_TO_COUNT)?;
+    let mut wr_deleted = wr.open_table(*DELETED)?;
+
+    if all {
+        let count = wr_c2u.len()?;
+        if hard_delete {
+            wr_deleted.retain(|_, _| false)?;
+        } else {
+            for res in wr_c2u.iter()? {
+                let (code, url) = res?;
+                wr_deleted.insert(code.value(), url.value())?;
+            }
+        }
+        wr_c2u.retain(|_, _| false)?;
+        wr_u2c.retain(|_, _| false)?;
+        wr_c2o.retain(|_, _| false)?;
+        wr_c2t.retain(|_, _| false)?;
+        wr_tags.retain(|_, _| false)?;
+        wr_status.retain(|_, _| false)?;
+        wr_c2ip.retain(|_, _| false)?;
+        wr_ip2n.retain(|_, _| false)?;
+        println!("removed {} mapping{}", count, if count == 1 { "" } else { "s" });
+    } else {
+        let code = code.unwrap();
+        match wr_c2u.remove(code.as_str())?.map(|url| url.value().to_string()) {
+            Some(url) => {
+                wr_u2c.remove(url.as_str())?;
+                wr_c2o.remove(code.as_str())?;
+                wr_c2t.remove(code.as_str())?;
+                wr_tags.remove(code.as_str())?;
+                wr_status.remove(code.as_str())?;
+                release_creator_quota(&mut wr_c2ip, &mut wr_ip2n, code.as_str())?;
+                if !hard_delete {
+                    wr_deleted.insert(code.as_str(), url.as_str())?;
+                }
+                println!("removed mapping {} -> {}", code, url);
+            }
+            None if hard_delete && wr_deleted.remove(code.as_str())?.is_some() => {
+                println!("removed tombstone for {}", code);
+            }
+            None => {
+                println!("code not found: {}", code);
+                return Ok(());
+            }
+        }
+    }
+
+    drop(wr_c2u);
+    drop(wr_u2c);
+    drop(wr_c2o);
+    drop(wr_c2t);
+    drop(wr_tags);
+    drop(wr_status);
+    drop(wr_c2ip);
+    drop(wr_ip2n);
+    drop(wr_deleted);
+
+    wr.commit()?;
+    db.compact()?;
+
+    Ok(())
+}
+
+fn import_urls(
+    path: PathBuf,
+    file: PathBuf,
+    code_prefix: String,
+    code_profile: CodeProfile,
+    code_reserved: Option<PathBuf>
+) -> Result<()> {
+    check_code_prefix(&code_prefix);
+    if !file.is_file() {
+        eprintln!("url file does not exist or is not a file: {}", file.display());
+        std::process::exit(1);
+    }
+    let reserved = read_reserved_codes(code_reserved.as_deref());
+    let urls = fs::read_to_string(&file)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let db = Database::create(&path)?;
+    init_tables(&db)?;
+
+    let wr = db.begin_write()?;
+    let mut wr_c2u = wr.open_table(*CODE_TO_URL)?;
+    let mut wr_u2c = wr.open_table(*URL_TO_CODE)?;
+    let mut wr_c2t = wr.open_table(*CREATED)?;
+    let wr_deleted = wr.open_table(*DELETED)?;
+
+    let (mut created, mut existing, mut invalid) = (0, 0, 0);
+    for line in urls.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let url = match normalize_url(line) {
+            Ok((_, url)) => url,
+            Err(msg) => {
+                println!("  {}: {}", line, msg);
+                invalid += 1;
+                continue;
+            }
+        };
+
+        if let Some(code) = wr_u2c.get(url.as_str())? {
+            println!("  {} -> {} (existing)", url, code.value());
+            existing += 1;
+            continue;
+        }
+
+        let code = unused_code(&wr_c2u, &wr_deleted, || new_code(&code_prefix, code_profile, &reserved, &mut rand::rng()))?;
+        wr_c2u.insert(code.as_str(), url.as_str())?;
+        wr_u2c.insert(url.as_str(), code.as_str())?;
+        wr_c2t.insert(code.as_str(), now_millis())?;
+        println!("  {} -> {}", url, code);
+        created += 1;
+    }
+
+    drop(wr_c2u);
+    drop(wr_u2c);
+    drop(wr_c2t);
+    drop(wr_deleted);
+
+    wr.commit()?;
+
+    println!("imported {}: {} created, {} existing, {} invalid", file.display(), created, existing, invalid);
+
+    Ok(())
+}
+
+fn audit(path: PathBuf, deny_empty_path_targets: bool, delete: bool) -> Result<()> {
+    if !path.is_file() {
+        eprintln!("database file does not exist or is not a file: {}", path.display());
+        std::process::exit(1);
+    }
+    let db = open_db(&path)?;
+    let rd = db.begin_read()?;
+    let rd_c2u = rd.open_table(*CODE_TO_URL)?;
+    let rd_c2o = rd.open_table(*ORIGINAL_URL)?;
+
+    let (mut passed, mut failed) = (0, Vec::new());
+    for res in rd_c2u.iter()? {
+        let (code, url) = res?;
+        // the original is what gets redirected to, so that's what has to pass
+        let target = match rd_c2o.get(code.value())? {
+            Some(original) => original.value().to_string(),
+            None => url.value().to_string(),
+        };
+
+        match check_target(&target, deny_empty_path_targets) {
+            Ok(()) => passed += 1,
+            Err(msg) => {
+                println!("  {} -> {}: {}", code.value(), target, msg);
+                failed.push((code.value().to_string(), url.value().to_string()));
+            }
+        }
+    }
+    drop(rd_c2u);
+    drop(rd_c2o);
+    drop(rd);
+
+    println!("audited {}: {} passed, {} failed", path.display(), passed, failed.len());
+    if !delete || failed.is_empty() {
+        return Ok(());
+    }
+
+    let wr = db.begin_write()?;
+    let mut wr_c2u = wr.open_table(*CODE_TO_URL)?;
+    let mut wr_u2c = wr.open_table(*URL_TO_CODE)?;
+    let mut wr_c2o = wr.open_table(*ORIGINAL_URL)?;
+    let mut wr_c2t = wr.open_table(*CREATED)?;
+    let mut wr_tags = wr.open_table(*TAGS)?;
+    let mut wr_status = wr.open_table(*STATUS)?;
+    let mut wr_c2ip = wr.open_table(*CODE_TO_CREATOR_IP)?;
+    let mut wr_ip2n = wr.open_table(*CREATOR_IP_TO_COUNT)?;
+    let mut wr_deleted = wr.open_table(*DELETED)?;
+
+    for (code, url) in &failed {
+        wr_c2u.remove(code.as_str())?;
+        wr_u2c.remove(url.as_str())?;
+        wr_c2o.remove(code.as_str())?;
+        wr_c2t.remove(code.as_str())?;
+        wr_tags.remove(code.as_str())?;
+        wr_status.remove(code.as_str())?;
+        release_creator_quota(&mut wr_c2ip, &mut wr_ip2n, code.as_str())?;
+        wr_deleted.insert(code.as_str(), url.as_str())?;
+    }
+
+    drop(wr_c2u);
+    drop(wr_u2c);
+    drop(wr_c2o);
+    drop(wr_c2t);
+    drop(wr_tags);
+    drop(wr_status);
+    drop(wr_c2ip);
+    drop(wr_ip2n);
+    drop(wr_deleted);
+
+    wr.commit()?;
+    println!("removed {} mapping{}", failed.len(), if failed.len() == 1 { "" } else { "s" });
+
+    Ok(())
+}
+
+fn rebuild_reverse(path: PathBuf) -> Result<()> {
+    if !path.is_file() {
+        eprintln!("database file does not exist or is not a file: {}", path.display());
+        std::process::exit(1);
+    }
+    let db = open_db(&path)?;
+    let wr = db.begin_write()?;
+    let (rebuilt, conflicts) = {
+        let wr_c2u = wr.open_table(*CODE_TO_URL)?;
+        let mut wr_u2c = wr.open_table(*URL_TO_CODE)?;
+
+        let mut conflicts = 0;
+        let mut codes = BTreeMap::<String, Vec<String>>::new();
+        for res in wr_c2u.iter()? {
+            let (code, url) = res?;
+            codes.entry(url.value().to_string()).or_default().push(code.value().to_string());
+        }
+
+        // where a url has several codes, keep the one it already had if that's one of them,
+        // so existing clients keep getting the same code back
+        let mut entries = Vec::with_capacity(codes.len());
+        for (url, codes) in codes {
+            let kept = match wr_u2c.get(url.as_str())? {
+                Some(code) if codes.iter().any(|c| c == code.value()) => code.value().to_string(),
+                _ => codes[0].clone(),
+            };
+            if codes.len() > 1 {
+                println!("  conflict: {} has codes {}, keeping {}", url, codes.join(", "), kept);
+                conflicts += 1;
+            }
+            entries.push((url, kept));
+        }
+
+        wr_u2c.retain(|_, _| false)?;
+        for (url, code) in &entries {
+            wr_u2c.insert(url.as_str(), code.as_str())?;
+        }
+        (entries.len(), conflicts)
+    };
+    wr.commit()?;
+
+    println!("rebuilt reverse table in {}: {} urls, {} conflicts", path.display(), rebuilt, conflicts);
+    Ok(())
+}
+
+fn gc(path: PathBuf, dry_run: bool) -> Result<()> {
+    if !path.is_file() {
+        eprintln!("database file does not exist or is not a file: {}", path.display());
+        std::process::exit(1);
+    }
+    let db = open_db(&path)?;
+    let wr = db.begin_write()?;
+    let (mut orphaned, mut relinked, mut extras) = (0, 0, 0);
+    {
+        let wr_c2u = wr.open_table(*CODE_TO_URL)?;
+        let mut wr_u2c = wr.open_table(*URL_TO_CODE)?;
+
+        // reverse entries for codes that are gone or now point somewhere else
+        let mut stale = Vec::new();
+        for res in wr_u2c.iter()? {
+            let (url, code) = res?;
+            if wr_c2u.get(code.value())?.is_none_or(|u| u.value() != url.value()) {
+                println!("  orphaned reverse entry: {} -> {}", url.value(), code.value());
+                stale.push(url.value().to_string());
+            }
+        }
+        for url in &stale {
+            wr_u2c.remove(url.as_str())?;
+        }
+        orphaned += stale.len();
+
+        // codes whose url has no reverse entry, so it would get a second code
+        let mut missing = Vec::new();
+        for res in wr_c2u.iter()? {
+            let (code, url) = res?;
+            if wr_u2c.get(url.value())?.is_none() {
+                println!("  missing reverse entry: {} -> {}", code.value(), url.value());
+                missing.push((code.value().to_string(), url.value().to_string()));
+            }
+        }
+        for (code, url) in &missing {
+            if wr_u2c.get(url.as_str())?.is_none() {
+                wr_u2c.insert(url.as_str(), code.as_str())?;
+            }
+        }
+        relinked += missing.len();
+
+        // per-code data left behind for codes that don't exist
+        macro_rules! clear_orphans {
+            ($table:expr) => {{
+                let mut tb = wr.open_table($table)?;
+                let before = tb.len()?;
+                tb.retain(|code, _| wr_c2u.get(code).is_ok_and(|u| u.is_some()))?;
+                extras += (before - tb.len()?) as usize;
+            }};
+        }
+        clear_orphans!(*ORIGINAL_URL);
+        clear_orphans!(*CREATED);
+        clear_orphans!(*TAGS);
+        clear_orphans!(*STATUS);
+        clear_orphans!(*CODE_TO_CREATOR_IP);
+    }
+
+    println!("gc {}: {} orphaned reverse entries, {} missing reverse entries, {} leftover per-code entries{}",
+             path.display(), orphaned, relinked, extras, if dry_run { " (dry run, nothing changed)" } else { "" });
+    if dry_run {
+        wr.abort()?;
+    } else {
+        wr.commit()?;
+    }
+
+    Ok(())
+}
+
+fn merge(
+    path: PathBuf,
+    source: PathBuf,
+    on_conflict: OnConflict,
+    code_prefix: String,
+    code_profile: CodeProfile,
+    code_reserved: Option<PathBuf>
+) -> Result<()> {
+    for p in [&path, &source] {
+        if !p.is_file() {
+            eprintln!("database file does not exist or is not a file: {}", p.display());
+            std::process::exit(1);
+        }
+    }
+    check_code_prefix(&code_prefix);
+    let reserved = read_reserved_codes(code_reserved.as_deref());
+
+    let source_db = Database::open(&source)?;
+    let rd = source_db.begin_read()?;
+    let rd_c2u = rd.open_table(*CODE_TO_URL)?;
+    let db = open_db(&path)?;
+
+    // find conflicts before writing anything, so an error leaves the database untouched
+    if let OnConflict::Error = on_conflict {
+        let rd_dest = db.begin_read()?;
+        let (dest_c2u, dest_u2c, dest_deleted) =
+            (rd_dest.open_table(*CODE_TO_URL)?, rd_dest.open_table(*URL_TO_CODE)?, rd_dest.open_table(*DELETED)?);
+
+        let mut conflicts = 0;
+        for res in rd_c2u.iter()? {
+            let (code, url) = res?;
+            if dest_u2c.get(url.value())?.is_some() {
+                continue;
+            }
+            let existing = dest_c2u.get(code.value())?.map(|u| u.value().to_string());
+            if existing.is_some() || dest_deleted.get(code.value())?.is_some() {
+                println!("  conflict: {} -> {} (here: {})",
+                         code.value(), url.value(), existing.as_deref().unwrap_or("removed"));
+                conflicts += 1;
+            }
+        }
+
+        if conflicts > 0 {
+            eprintln!("{} conflicting code{}, nothing merged", conflicts, if conflicts == 1 { "" } else { "s" });
+            std::process::exit(1);
+        }
+    }
+
+    let extras = (
+        open_optional(&rd, *ORIGINAL_URL)?,
+        open_optional(&rd, *CREATED)?,
+        open_optional(&rd, *TAGS)?,
+        open_optional(&rd, *STATUS)?,
+    );
+    let gen_code = || new_code(&code_prefix, code_profile, &reserved, &mut rand::rng());
+
+    let (mut merged, mut skipped, mut regenerated) = (0, 0, 0);
+    let mut iter = rd_c2u.iter()?;
+    loop {
+        let mut batch = Vec::with_capacity(MERGE_BATCH_SIZE);
+        for res in iter.by_ref().take(MERGE_BATCH_SIZE) {
+            let (code, url) = res?;
+            batch.push((code.value().to_string(), url.value().to_string()));
+        }
+        if batch.is_empty() {
+            break;
+        }
+
+        let wr = db.begin_write()?;
+        {
+            let mut wr_c2u = wr.open_table(*CODE_TO_URL)?;
+            let mut wr_u2c = wr.open_table(*URL_TO_CODE)?;
+            let mut wr_c2o = wr.open_table(*ORIGINAL_URL)?;
+            let mut wr_c2t = wr.open_table(*CREATED)?;
+            let mut wr_tags = wr.open_table(*TAGS)?;
+            let mut wr_status = wr.open_table(*STATUS)?;
+            let wr_deleted = wr.open_table(*DELETED)?;
+
+            for (code, url) in batch {
+                // the url already has a code here, keep that one
+                if wr_u2c.get(url.as_str())?.is_some() {
+                    skipped += 1;
+                    continue;
+                }
+
+                let taken = wr_c2u.get(code.as_str())?.is_some() || wr_deleted.get(code.as_str())?.is_some();
+                let new_code = match on_conflict {
+                    _ if !taken => code.clone(),
+                    OnConflict::Regenerate => {
+                        let new_code = unused_code(&wr_c2u, &wr_deleted, &gen_code)?;
+                        println!("  regenerated: {} -> {} (was {})", new_code, url, code);
+                        regenerated += 1;
+                        new_code
+                    }
+                    // checked for above, but another writer may have added it since
+                    OnConflict::Skip | OnConflict::Error => {
+                        println!("  skipped: {} -> {} (code taken)", code, url);
+                        skipped += 1;
+                        continue;
+                    }
+                };
+
+                wr_c2u.insert(new_code.as_str(), url.as_str())?;
+                wr_u2c.insert(url.as_str(), new_code.as_str())?;
+                if let Some(tb) = &extras.0 && let Some(original) = tb.get(code.as_str())? {
+                    wr_c2o.insert(new_code.as_str(), original.value())?;
+                }
+                if let Some(tb) = &extras.1 && let Some(created) = tb.get(code.as_str())? {
+                    wr_c2t.insert(new_code.as_str(), created.value())?;
+                }
+                if let Some(tb) = &extras.2 && let Some(tags) = tb.get(code.as_str())? {
+                    wr_tags.insert(new_code.as_str(), tags.value())?;
+                }
+                if let Some(tb) = &extras.3 && let Some(permanent) = tb.get(code.as_str())? {
+                    wr_status.insert(new_code.as_str(), permanent.value())?;
+                }
+                merged += 1;
+            }
+        }
+        wr.commit()?;
+    }
+
+    println!("merged {}: {} merged, {} skipped, {} regenerated", source.display(), merged, skipped, regenerated);
+
+    Ok(())
+}
+
+fn restore(
+    path: PathBuf,
+    backup: PathBuf,
+    overwrite: bool
+) -> Result<()> {
+    for p in [&path, &backup] {
+        if !p.is_file() {
+            eprintln!("database file does not exist or is not a file: {}", p.display());
+            std::process::exit(1);
+        }
+    }
+    let backup_db = Database::open(&backup)?;
+    let rd = backup_db.begin_read()?;
+    let rd_c2u = rd.open_table(*CODE_TO_URL)?;
+    // backups may be from before these tables existed
+    let rd_c2o = open_optional(&rd, *ORIGINAL_URL)?;
+    let rd_c2t = open_optional(&rd, *CREATED)?;
+    let rd_tags = open_optional(&rd, *TAGS)?;
+    let rd_status = open_optional(&rd, *STATUS)?;
+    let rd_deleted = open_optional(&rd, *DELETED)?;
+
+    let db = Database::open(&path)?;
+    let wr = db.begin_write()?;
+    let mut wr_c2u = wr.open_table(*CODE_TO_URL)?;
+    let mut wr_u2c = wr.open_table(*URL_TO_CODE)?;
+    let mut wr_c2o = wr.open_table(*ORIGINAL_URL)?;
+    let mut wr_c2t = wr.open_table(*CREATED)?;
+    let mut wr_tags = wr.open_table(*TAGS)?;
+    let mut wr_status = wr.open_table(*STATUS)?;
+    let mut wr_deleted = wr.open_table(*DELETED)?;
+
+    let (mut inserted, mut skipped, mut conflicts) = (0, 0, 0);
+    for res in rd_c2u.iter()? {
+        let (code, url) = res?;
+        let (code, url) = (code.value(), url.value());
+
+        // removed since the backup was taken, don't bring it back
+        if wr_deleted.get(code)?.is_some() {
+            skipped += 1;
+            continue;
+        }
+
+        let existing = wr_c2u.get(code)?.map(|u| u.value().to_string());
+        match existing {
+            None => {}
+            Some(existing) if existing == url => {
+                skipped += 1;
+                continue;
+            }
+            Some(existing) => {
+                conflicts += 1;
+                if !overwrite {
+                    println!("  conflict: {} -> {} (backup has {})", code, existing, url);
+                    continue;
+                }
+                println!("  overwriting: {} -> {} (was {})", code, url, existing);
+                if wr_u2c.get(existing.as_str())?.is_some_and(|c| c.value() == code) {
+                    wr_u2c.remove(existing.as_str())?;
+                }
+            }
+        }
+
+        wr_c2u.insert(code, url)?;
+        if wr_u2c.get(url)?.is_none() {
+            wr_u2c.insert(url, code)?;
+        }
+
+        let original = match &rd_c2o {
+            Some(tb) => tb.get(code)?.map(|o| o.value().to_string()),
+            None => None,
+        };
+        match original {
+            Some(original) => wr_c2o.insert(code, original.as_str())?,
+            None => wr_c2o.remove(code)?,
+        };
+
+        let created = match &rd_c2t {
+            Some(tb) => tb.get(code)?.map(|t| t.value()),
+            None => None,
+        };
+        match created {
+            Some(created) => wr_c2t.insert(code, created)?,
+            None => wr_c2t.remove(code)?,
+        };
+
+        let tags = match &rd_tags {
+            Some(tb) => tb.get(code)?.map(|t| t.value().to_string()),
+            None => None,
+        };
+        match tags {
+            Some(tags) => wr_tags.insert(code, tags.as_str())?,
+            None => wr_tags.remove(code)?,
+        };
+
+        let permanent = match &rd_status {
+            Some(tb) => tb.get(code)?.map(|p| p.value()),
+            None => None,
+        };
+        match permanent {
+            Some(permanent) => wr_status.insert(code, permanent)?,
+            None => wr_status.remove(code)?,
+        };
+        inserted += 1;
+    }
+
+    if let Some(rd_deleted) = &rd_deleted {
+        for res in rd_deleted.iter()? {
+            let (code, url) = res?;
+            if wr_c2u.get(code.value())?.is_none() {
+                wr_deleted.insert(code.value(), url.value())?;
+            }
+        }
+    }
+
+    drop(wr_c2u);
+    drop(wr_u2c);
+    drop(wr_c2o);
+    drop(wr_c2t);
+    drop(wr_tags);
+    drop(wr_status);
+    drop(wr_deleted);
+
+    wr.commit()?;
+
+    println!("restored from {}: {} inserted, {} skipped, {} conflict{}{}",
+             backup.display(), inserted, skipped, conflicts, if conflicts == 1 { "" } else { "s" },
+             if conflicts > 0 && !overwrite { " (not overwritten, use --overwrite to replace)" } else { "" });
+
+    Ok(())
+}
+
+async fn serve(path: PathBuf, args: ServeArgs) -> Result<()> {
+    let ServeArgs {
+        url, metrics_port, latency_histogram_buckets, metrics_file, metrics_interval, index, ui, open, systemd, code_prefix, code_profile, seed, code_reserved,
+        keep_original, no_double_slash_normalize, db_check_interval, base_url,
+        redirect_loop_max, relative_base,
+        put_status, deny_empty_path_targets, validate_utf8_strict, x_robots_tag, no_x_robots_tag, maintenance, maintenance_message,
+        maintenance_page, validate_target_url, validate_timeout_ms, request_timeout, disable_redirect, suggest, preview_font,
+        preview_delay, max_redirects_per_sec,
+        cache_size, cache_ttl, durability, max_codes_per_ip, write_timeout_ms, log_rejected_urls, response_id_length, json_response_indent,
+        db_reopen_on_error, db_error_threshold, db_error_window_secs, db_reopen_delay_ms,
+        response_id_format, request_id_propagate, request_id_max_length, log_slow_requests_ms,
+        log_very_slow_requests_ms, log_sampled_bodies, body_sampling_rate, security_headers, expose_version_header, server_header,
+        fix_malformed_codes, path_normalization, strip_suffixes, sqlite_compat_export, startup_check, startup_script, namespace,
+        healthcheck_extra_tables, shutdown_hook, shutdown_hook_timeout_secs,
+        #[cfg(feature = "sentry")] sentry_dsn,
+        #[cfg(feature = "sentry")] sentry_environment,
+        #[cfg(feature = "sentry")] sentry_release,
+    } = args;
+
+    // reports panics through its default integrations until this is dropped at the end of serve
+    #[cfg(feature = "sentry")]
+    let _sentry = sentry_dsn.as_deref().map(|dsn| sentry::init((dsn, sentry::ClientOptions {
+        environment: sentry_environment.map(Into::into),
+        release: sentry_release.map(Into::into),
+        ..Default::default()
+    })));
+
+    check_code_prefix(&code_prefix);
+
+    if let Some(base) = &relative_base && !ALLOWED_SCHEMES.contains(&base.scheme()) {
+        eprintln!("unsupported relative base scheme: {}", base.scheme());
+        std::process::exit(1);
+    }
+
+    let robots_tag = match HeaderValue::from_str(&x_robots_tag) {
+        Ok(_) if no_x_robots_tag => None,
+        Ok(value) => Some(value),
+        Err(_) => {
+            eprintln!("invalid X-Robots-Tag value: {}", x_robots_tag);
+            std::process::exit(1);
+        }
+    };
+
+    // hyper doesn't send a Server header on its own, so there's nothing to strip without these
+    let server_header = match server_header {
+        Some(value) => match HeaderValue::from_str(&value) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                eprintln!("invalid Server header value: {}", value);
+                std::process::exit(1);
+            }
+        },
+        None if expose_version_header => Some(HeaderValue::from_static(concat!("cc/", env!("CARGO_PKG_VERSION")))),
+        None => None,
+    };
+
+    for (i, (name, _)) in namespace.iter().enumerate() {
+        if !is_code(name) || RESERVED_CODES.contains(&name.as_str()) {
+            eprintln!("invalid namespace name: {}", name);
+            std::process::exit(1);
+        }
+        if namespace[..i].iter().any(|(other, _)| other == name) {
+            eprintln!("duplicate namespace: {}", name);
+            std::process::exit(1);
+        }
+    }
+
+    // check everything that can be checked before setting anything up, so a bad config doesn't half-start
+    let files = [
+        ("index file", &index), ("maintenance page", &maintenance_page), ("startup script", &startup_script),
+        ("reserved codes file", &code_reserved),
+    ];
+    for (what, file) in files {
+        if let Some(file) = file && let Err(e) = fs::read_to_string(file) {
+            eprintln!("{} {} can't be read: {}", what, file.display(), e);
+            std::process::exit(1);
+        }
+    }
+    for db_path in std::iter::once(&path).chain(namespace.iter().map(|(_, p)| p)) {
+        // a check shouldn't leave new databases (or directories) behind
+        if startup_check && !db_path.is_file() {
+            eprintln!("database file does not exist or is not a file: {}", db_path.display());
+            std::process::exit(1);
+        }
+        if let Err(e) = check_db_writable(db_path, startup_check) {
+            eprintln!("database {} isn't writable: {}", db_path.display(), e);
+            std::process::exit(1);
+        }
+    }
+
+    if matches!(response_id_format, RequestIdFormat::Uuid) && response_id_length != 32 {
+        eprintln!("--response-id-format uuid needs --response-id-length 32");
+        std::process::exit(1);
+    }
+
+    if metrics_port == Some(url.port()) {
+        eprintln!("--metrics-port can't be the same as the main port ({})", url.port());
+        std::process::exit(1);
+    }
+
+    let preview_font = match &preview_font {
+        Some(file) => match ab_glyph::FontArc::try_from_vec(fs::read(file)?) {
+            Ok(font) => Some(font),
+            Err(e) => {
+                eprintln!("preview font {} can't be loaded: {}", file.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    if startup_check {
+        println!("startup check passed");
+        return Ok(());
+    }
+
+    let target_client = if validate_target_url {
+        Some(reqwest::Client::builder().timeout(Duration::from_millis(validate_timeout_ms)).build()?)
+    } else {
+        None
+    };
+
+    let reserved = Arc::new(RwLock::new(match &code_reserved {
+        Some(file) => load_reserved_codes(file)?,
+        None => HashSet::new(),
+    }));
+
+    let redirect_limit = (max_redirects_per_sec > 0).then(|| Arc::new(Mutex::new(TokenBucket::new(max_redirects_per_sec))));
+    let self_hosts = self_hosts(url, base_url.as_ref());
+    let rng = seed.map(|seed| Arc::new(Mutex::new(StdRng::seed_from_u64(seed))));
+    let new_state = |db: Database, db_path: &std::path::Path, mirror: Option<SqliteMirror>| Arc::new(AppState {
+        db: ArcSwapOption::from_pointee(db),
+        reopen: db_reopen_on_error.then(|| DbReopen {
+            path: db_path.to_path_buf(),
+            threshold: db_error_threshold,
+            window: Duration::from_secs(db_error_window_secs),
+            delay: Duration::from_millis(db_reopen_delay_ms),
+            errors: Mutex::new(VecDeque::new()),
+            reopening: AtomicBool::new(false),
+        }),
+        mirror,
+        code_prefix: code_prefix.clone(),
+        code_profile,
+        reserved: reserved.clone(),
+        rng: rng.clone(),
+        keep_original,
+        raw_urls: no_double_slash_normalize,
+        put_status,
+        base_url: base_url.clone(),
+        self_hosts: self_hosts.clone(),
+        redirect_loop_max,
+        relative_base: relative_base.clone(),
+        deny_empty_path_targets,
+        validate_utf8_strict,
+        target_client: target_client.clone(),
+        log_rejected_urls,
+        health_tables: healthcheck_extra_tables.clone(),
+        disable_redirect,
+        suggest,
+        preview_delay,
+        redirect_limit: redirect_limit.clone(),
+        preview: preview_font.clone().map(|font| PreviewCards { font, rendered: Mutex::new(HashMap::new()) }),
+        cache: (cache_size > 0).then(|| RedirectCache::new(cache_size, Duration::from_secs(cache_ttl))),
+        durability,
+        max_codes_per_ip,
+        write_timeout: Duration::from_millis(write_timeout_ms),
+        db_healthy: AtomicBool::new(true),
+        redirects: AtomicU64::new(0),
+        created: AtomicU64::new(0),
+        status_mappings: AtomicU64::new(0),
+        started: Instant::now(),
+        db_errors: AtomicU64::new(0),
+    });
+
+    // the main database is served at the root, namespaces under /{name}/...
+    let db = open_db(&path)?;
+    let mirror = match &sqlite_compat_export {
+        Some(file) => match SqliteMirror::start(file, &db) {
+            Ok(mirror) => Some(mirror),
+            Err(e) => {
+                eprintln!("sqlite export {} can't be set up: {}", file.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut states = vec![(String::new(), new_state(db, &path, mirror))];
+    for (name, ns_path) in &namespace {
+        states.push((name.clone(), new_state(open_db(ns_path)?, ns_path, None)));
+    }
+
+    if let Some(script) = &startup_script
+        && let Err(e) = run_startup_script(script, states[0].1.clone())
+    {
+        eprintln!("startup script failed: {}", e);
+        std::process::exit(1);
+    }
+
+    spawn_stats_dump(states.clone());
+    if let Some(file) = &metrics_file {
+        spawn_metrics_file(states.clone(), file.clone(), metrics_interval);
+    }
+    let metrics_states = states.clone();
+    if let Some(file) = &code_reserved {
+        println!("{} reserved codes loaded from {}", reserved.read().unwrap().len(), file.display());
+        spawn_reserved_reload(file.clone(), reserved.clone());
+    }
+
+    let redirect_latency = Arc::new(LatencyHistogram::new(
+        "cc_redirect_duration_seconds", "Time to answer GET /{code}.", &latency_histogram_buckets,
+    ));
+    let put_latency = Arc::new(LatencyHistogram::new(
+        "cc_put_duration_seconds", "Time to answer POST /put.", &latency_histogram_buckets,
+    ));
+    let histograms = [redirect_latency.clone(), put_latency.clone()];
+    let timed = |histogram: Arc<LatencyHistogram>| middleware::from_fn(move |req: Request, next: Next| {
+        let histogram = histogram.clone();
+        async move {
+            let start = Instant::now();
+            let res = next.run(req).await;
+            histogram.observe(start.elapsed());
+            res
+        }
+    });
+
+    let mut app = Router::new();
+    for (name, state) in states {
+        if db_check_interval > 0 {
+            spawn_db_check(state.clone(), db_check_interval, name.clone());
+        }
+
+        spawn_status_refresh(state.clone());
+
+        let mut routes = Router::new()
+            .route("/put", post(put_new).layer(timed(put_latency.clone())))
+            .route("/health", get(health))
+            .route("/status", get(status))
+            .route("/admin/exists", post(codes_exist))
+            .route("/{code}", get(get_code).layer(timed(redirect_latency.clone())));
+        if state.preview.is_some() {
+            routes = routes.route("/{code}/preview.png", get(preview_card));
+        }
+        let routes = routes.with_state(state);
+
+        app = if name.is_empty() {
+            app.merge(routes)
+        } else {
+            app.nest(&format!("/{}", name), routes)
+        };
+    }
+
+    let index = match &index {
+        Some(index) => {
+            // http dates only have second precision
+            let modified = fs::metadata(index)?.modified().ok()
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs()));
+            Some(IndexPage { html: fs::read_to_string(index)?, modified })
+        }
+        None if ui => Some(IndexPage { html: BUILTIN_UI.to_string(), modified: None }),
+        None => None,
+    };
+
+    if let Some(index) = index {
+        let index = Arc::new(index);
+        app = app.route("/", get(move |Query(query): Query<IndexQuery>, headers: HeaderMap| {
+            let index = index.clone();
+            async move { serve_index(&index, query, &headers) }
+        }));
+    }
+
+    app = app.fallback_service(get(|| async { StatusCode::NOT_FOUND }));
+
+    if seed.is_some() {
+        println!("warning: --seed makes codes predictable, only use it for tests");
+    }
+
+    if let DurabilityLevel::None = durability {
+        println!("warning: --durability none, codes created just before a crash can be lost");
+    }
+
+    if disable_redirect {
+        println!("api-only mode: GET /{{code}} answers with json instead of redirecting");
+    } else if preview_delay > 0 {
+        println!("GET /{{code}} shows the target for {}s before redirecting", preview_delay);
+    }
+
+    if maintenance {
+        let page = match &maintenance_page {
+            Some(page) => Some(Arc::new(fs::read_to_string(page)?)),
+            None => None,
+        };
+
+        println!("maintenance mode: all requests except /health get 503");
+        app = app.layer(middleware::from_fn(move |req: Request, next: Next| {
+            let (page, message) = (page.clone(), maintenance_message.clone());
+            async move {
+                // namespaces have their own /{name}/health
+                if req.uri().path().ends_with("/health") {
+                    return next.run(req).await;
+                }
+
+                let body = match page {
+                    Some(page) => Html(page.to_string()).into_response(),
+                    None => Json(Response { ok: false, msg: message }).into_response(),
+                };
+                (StatusCode::SERVICE_UNAVAILABLE, body).into_response()
+            }
+        }));
+    }
+
+    if fix_malformed_codes {
+        app = app.layer(middleware::from_fn(|req: Request, next: Next| async move {
+            if req.method() == Method::GET && let Some(fixed) = fix_code_path(req.uri().path()) {
+                return Redirect::permanent(&fixed).into_response();
+            }
+            next.run(req).await
+        }));
+    }
+
+    // a timed out handler is dropped before it commits, which aborts its write transaction,
+    // so nothing is left half-written
+    if let Some(secs) = request_timeout {
+        app = app.layer(TimeoutLayer::with_status_code(StatusCode::GATEWAY_TIMEOUT, Duration::from_secs(secs)));
+    }
+
+    if let Some(tag) = robots_tag {
+        app = app.layer(middleware::map_response(move |mut res: AxumResponse| {
+            let tag = tag.clone();
+            async move {
+                if res.status().is_success() || res.status().is_redirection() {
+                    res.headers_mut().insert(X_ROBOTS_TAG, tag);
+                }
+                res
+            }
+        }));
+    }
+
+    if security_headers {
+        app = app.layer(middleware::map_response(|mut res: AxumResponse| async move {
+            let is_html = res.headers().get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.starts_with("text/html"));
+            if is_html {
+                let headers = res.headers_mut();
+                headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+                headers.insert(REFERRER_POLICY, HeaderValue::from_static("no-referrer"));
+                headers.insert(CONTENT_SECURITY_POLICY, HeaderValue::from_static(HTML_CSP));
+            }
+            res
+        }));
+    }
+
+    if json_response_indent > 0 {
+        app = app.layer(middleware::map_response(move |res: AxumResponse| async move {
+            let is_json = res.headers().get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.starts_with("application/json"));
+            if !is_json {
+                return res;
+            }
+
+            // responses are all small, so this just reads the whole thing
+            let (mut parts, body) = res.into_parts();
+            let body = match axum::body::to_bytes(body, usize::MAX).await {
+                Ok(body) => body,
+                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            };
+            match indent_json(&body, json_response_indent) {
+                Some(pretty) => {
+                    parts.headers.remove(CONTENT_LENGTH);
+                    AxumResponse::from_parts(parts, pretty.into())
+                }
+                None => AxumResponse::from_parts(parts, body.into()),
+            }
+        }));
+    }
+
+    if let Some(value) = server_header {
+        app = app.layer(middleware::map_response(move |mut res: AxumResponse| {
+            let value = value.clone();
+            async move {
+                res.headers_mut().insert(SERVER, value);
+                res
+            }
+        }));
+    }
+
+    #[cfg(feature = "sentry")]
+    if sentry_dsn.is_some() {
+        app = app.layer(middleware::from_fn(|req: Request, next: Next| async move {
+            let (method, path) = (req.method().clone(), req.uri().path().to_string());
+            let res = next.run(req).await;
+            if res.status().is_server_error() {
+                sentry::with_scope(|scope| {
+                    scope.set_tag("method", method.as_str());
+                    scope.set_tag("path", &path);
+                    scope.set_tag("status", res.status().as_u16());
+                }, || sentry::capture_message(&format!("{} {} returned {}", method, path, res.status()), sentry::Level::Error));
+            }
+            res
+        }));
+    }
+
+    app = app.layer(middleware::from_fn(move |mut req: Request, next: Next| async move {
+        let incoming = match req.headers().get(X_REQUEST_ID) {
+            Some(id) if request_id_propagate => match id.to_str() {
+                Ok(id) if is_request_id(id, request_id_max_length) => Some(id.to_string()),
+                _ => {
+                    println!("warning: ignoring malformed X-Request-ID on {} {}", req.method(), req.uri().path());
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let id = incoming.unwrap_or_else(|| gen_request_id(response_id_format, response_id_length));
+        req.extensions_mut().insert(RequestId(id.clone()));
+
+        let mut res = next.run(req).await;
+        if let Ok(id) = HeaderValue::from_str(&id) {
+            res.headers_mut().insert(X_REQUEST_ID, id);
+        }
+        res
+    }));
+
+    if log_slow_requests_ms > 0 || log_very_slow_requests_ms > 0 {
+        let slow = Duration::from_millis(log_slow_requests_ms);
+        let very_slow = Duration::from_millis(log_very_slow_requests_ms);
+        app = app.layer(middleware::from_fn(move |req: Request, next: Next| async move {
+            let (method, path) = (req.method().clone(), req.uri().path().to_string());
+            let start = Instant::now();
+            let res = next.run(req).await;
+            let elapsed = start.elapsed();
+
+            let id = res.headers().get(X_REQUEST_ID).and_then(|v| v.to_str().ok()).unwrap_or("-");
+            if !very_slow.is_zero() && elapsed >= very_slow {
+                println!("very slow request: {} {} took {}ms ({}, id {})", method, path, elapsed.as_millis(), res.status(), id);
+            } else if !slow.is_zero() && elapsed >= slow {
+                println!("slow request: {} {} took {}ms ({}, id {})", method, path, elapsed.as_millis(), res.status(), id);
+            }
+            res
+        }));
+    }
+
+    if log_sampled_bodies {
+        println!("warning: --log-sampled-bodies logs request bodies, which can hold private urls");
+        app = app.layer(middleware::from_fn(move |req: Request, next: Next| async move {
+            // GET is left out so redirects (the bulk of the traffic) aren't logged
+            if req.method() == Method::GET || !rand::rng().random_bool(body_sampling_rate) {
+                return next.run(req).await;
+            }
+
+            let (parts, body) = req.into_parts();
+            let body = match axum::body::to_bytes(body, MAX_SAMPLED_BODY_LEN).await {
+                Ok(body) => body,
+                Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+            };
+            let headers = redact_headers(&parts.headers);
+            let req_body = escape_body(&body);
+            let (method, path) = (parts.method.clone(), parts.uri.path().to_string());
+
+            let res = next.run(Request::from_parts(parts, body.into())).await;
+            let (parts, body) = res.into_parts();
+            let body = match axum::body::to_bytes(body, usize::MAX).await {
+                Ok(body) => body,
+                Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            };
+
+            let id = parts.headers.get(X_REQUEST_ID).and_then(|v| v.to_str().ok()).unwrap_or("-");
+            println!("sampled request: {} {} (id {}) headers {} body \"{}\" -> {} body \"{}\"",
+                     method, path, id, headers, req_body, parts.status, escape_body(&body));
+            AxumResponse::from_parts(parts, body.into())
+        }));
+    }
+
+    // this has to see the request before the router does, so the rewritten path is what gets routed
+    if path_normalization {
+        let suffixes = Arc::new(strip_suffixes);
+        app = Router::new().fallback_service(app).layer(middleware::from_fn(move |mut req: Request, next: Next| {
+            let suffixes = suffixes.clone();
+            async move {
+                if let Some(code) = strip_path_suffix(req.uri().path(), &suffixes) {
+                    let stripped = match req.uri().query() {
+                        Some(query) => format!("/{}?{}", code, query),
+                        None => format!("/{}", code),
+                    };
+                    if let Ok(uri) = stripped.parse() {
+                        println!("stripped suffix: {} -> /{}", req.uri().path(), code);
+                        *req.uri_mut() = uri;
+                    }
+                }
+                next.run(req).await
+            }
+        }));
+    }
+
+    let inherited = if systemd {
+        ListenFd::from_env().take_tcp_listener(0)?
+    } else {
+        None
+    };
+
+    let listener = match inherited {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            TcpListener::from_std(listener)?
+        }
+        None => {
+            if systemd {
+                println!("no socket passed by systemd, binding {}", url);
+            }
+            TcpListener::bind(url).await?
+        }
+    };
+
+    println!("Starting cc at http://{}, db at {}", listener.local_addr()?, path.display());
+    for (name, ns_path) in &namespace {
+        println!("  /{}/ -> db at {}", name, ns_path.display());
+    }
+    if open {
+        open_browser(listener.local_addr()?);
+    }
+    // both listeners stop on the same signal
+    let (stop, stopped) = tokio::sync::watch::channel(());
+    let metrics = match metrics_port {
+        Some(port) => {
+            let addr = SocketAddr::new(url.ip(), port);
+            let metrics_listener = TcpListener::bind(addr).await?;
+            println!("  metrics at http://{}/metrics", metrics_listener.local_addr()?);
+
+            let started = Instant::now();
+            let metrics_app = Router::new().route("/metrics", get(move || {
+                let (states, histograms) = (metrics_states.clone(), histograms.clone());
+                async move { render_metrics(&states, &histograms, started) }
+            }));
+            let mut stopped = stopped.clone();
+            Some(tokio::spawn(async move {
+                axum::serve(metrics_listener, metrics_app)
+                    .with_graceful_shutdown(async move { let _ = stopped.changed().await; })
+                    .await
+            }))
+        }
+        None => None,
+    };
+
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            let _ = stop.send(());
+        })
+        .await?;
+    if let Some(metrics) = metrics {
+        metrics.await??;
+    }
+
+    if let Some(hook) = shutdown_hook {
+        run_shutdown_hook(&hook, "signal", Duration::from_secs(shutdown_hook_timeout_secs)).await;
+    }
+
+    Ok(())
+}
+
+fn check_code_prefix(prefix: &str) {
+    if prefix.len() > MAX_CODE_PREFIX_LEN || !prefix.chars().all(is_code_char) {
+        eprintln!("code prefix must be at most {} url-safe characters (A-Z, a-z, 0-9, -, _): {}",
+                  MAX_CODE_PREFIX_LEN, prefix);
+        std::process::exit(1);
+    }
+}
+
+/// Resolves on ctrl-c, or SIGTERM on unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    println!("shutting down, waiting for in-flight requests");
+}
+
+/// Opens the server's root in the default browser, for `--open`.
+fn open_browser(addr: SocketAddr) {
+    if std::env::var_os("CI").is_some() {
+        println!("warning: --open skipped in CI");
+        return;
+    }
+    #[cfg(all(unix, not(target_os = "macos")))]
+    if std::env::var_os("DISPLAY").is_none() && std::env::var_os("WAYLAND_DISPLAY").is_none() {
+        println!("warning: --open skipped, no display");
+        return;
+    }
+
+    // a browser can't go to 0.0.0.0 or ::
+    let ip = match addr.ip() {
+        ip if !ip.is_unspecified() => ip,
+        std::net::IpAddr::V4(_) => std::net::Ipv4Addr::LOCALHOST.into(),
+        std::net::IpAddr::V6(_) => std::net::Ipv6Addr::LOCALHOST.into(),
+    };
+    let url = format!("http://{}/", SocketAddr::new(ip, addr.port()));
+    if let Err(e) = open::that_detached(&url) {
+        println!("warning: couldn't open a browser at {}: {}", url, e);
+    }
+}
+
+async fn run_shutdown_hook(cmd: &str, reason: &str, timeout: Duration) {
+    let child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("CC_SHUTDOWN_REASON", reason)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+
+    let child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            println!("failed to run shutdown hook: {}", e);
+            return;
+        }
+    };
+
+    match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(Ok(output)) => {
+            for line in String::from_utf8_lossy(&output.stdout).lines() {
+                println!("shutdown hook: {}", line);
+            }
+            for line in String::from_utf8_lossy(&output.stderr).lines() {
+                eprintln!("shutdown hook: {}", line);
+            }
+            if !output.status.success() {
+                println!("shutdown hook exited with {}", output.status);
+            }
+        }
+        Ok(Err(e)) => println!("shutdown hook failed: {}", e),
+        Err(_) => println!("shutdown hook timed out after {}s, exiting anyway", timeout.as_secs()),
+    }
+}
+
+/// Opens (or creates) a database for serving, making sure its tables exist.
+fn open_db(path: &std::path::Path) -> Result<Database> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let db = Database::create(path)?;
+    init_tables(&db)?;
+    Ok(db)
+}
+
+/// Opens a database and commits an empty write, for the startup checks. The database is closed again after.
+/// With `existing_only` (for `--startup-check`), a missing database is an error rather than created.
+fn check_db_writable(path: &std::path::Path, existing_only: bool) -> Result<()> {
+    let db = if existing_only { Database::open(path)? } else { open_db(path)? };
+    db.begin_write()?.commit()?;
+    Ok(())
+}
+
+/// Keeps the `/status` mapping count fresh, so the public endpoint never counts anything itself.
+fn spawn_status_refresh(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STATUS_REFRESH);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            // a failed count keeps the last one, /health is for reporting problems
+            if let Ok((codes, _)) = state.db().and_then(|db| check_db(&db)) {
+                state.status_mappings.store(codes, Ordering::Relaxed);
+            }
+        }
+    });
+}
+
+fn spawn_db_check(state: Arc<AppState>, interval_secs: u64, namespace: String) {
+    let label = if namespace.is_empty() { String::new() } else { format!(" (/{})", namespace) };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            match state.db().and_then(|db| check_db(&db)) {
+                Ok((codes, urls)) => {
+                    println!("db check ok{}: {} codes, {} urls", label, codes, urls);
+                    state.db_healthy.store(true, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    println!("db check failed{}: {}", label, e);
+                    state.db_healthy.store(false, Ordering::Relaxed);
+                }
+            }
+        }
+    });
+}
+
+/// Runs `--startup-script`, before anything else can touch the database.
+fn run_startup_script(path: &std::path::Path, state: Arc<AppState>) -> Result<()> {
+    let script = fs::read_to_string(path)?;
+    let mut engine = rhai::Engine::new();
+
+    let st = state.clone();
+    engine.register_fn("create_code", move |url: String| -> Result<String, Box<rhai::EvalAltResult>> {
+        script_create_code(&st, &url).map_err(|e| e.to_string().into())
+    });
+    let st = state.clone();
+    engine.register_fn("delete_code", move |code: String| -> Result<bool, Box<rhai::EvalAltResult>> {
+        script_delete_code(&st, &code).map_err(|e| e.to_string().into())
+    });
+    let st = state;
+    engine.register_fn("list_codes", move || -> Result<rhai::Array, Box<rhai::EvalAltResult>> {
+        script_list_codes(&st).map_err(|e| e.to_string().into())
+    });
+
+    let start = Instant::now();
+    engine.run(&script).map_err(|e| anyhow::anyhow!("{}", e))?;
+    println!("startup script completed in {}ms", start.elapsed().as_millis());
+
+    Ok(())
+}
+
+/// `create_code(url)` for startup scripts: the url's code, creating one if needed.
+fn script_create_code(state: &AppState, raw: &str) -> Result<String> {
+    let (_, url) = normalize_url(raw.trim()).map_err(anyhow::Error::msg)?;
+    let mut wr = state.db()?.begin_write()?;
+    wr.set_durability(state.durability.into())?;
+    let code = {
+        let mut wr_c2u = wr.open_table(*CODE_TO_URL)?;
+        let mut wr_u2c = wr.open_table(*URL_TO_CODE)?;
+        let mut wr_c2t = wr.open_table(*CREATED)?;
+        let wr_deleted = wr.open_table(*DELETED)?;
+
+        if let Some(code) = wr_u2c.get(url.as_str())?.map(|c| c.value().to_string()) {
+            code
+        } else {
+            let code = unused_code(&wr_c2u, &wr_deleted, || state.new_code())?;
+            wr_c2u.insert(code.as_str(), url.as_str())?;
+            wr_u2c.insert(url.as_str(), code.as_str())?;
+            wr_c2t.insert(code.as_str(), now_millis())?;
+            println!("  {} -> {}", url, code);
+            code
+        }
+    };
+    wr.commit()?;
+
+    if let Some(mirror) = &state.mirror {
+        mirror.stored(&code, &url);
+    }
+
+    Ok(code)
+}
+
+/// `delete_code(code)` for startup scripts: removes a mapping like `rm`, leaving a tombstone.
+fn script_delete_code(state: &AppState, code: &str) -> Result<bool> {
+    let wr = state.db()?.begin_write()?;
+    let removed = {
+        let mut wr_c2u = wr.open_table(*CODE_TO_URL)?;
+        let mut wr_u2c = wr.open_table(*URL_TO_CODE)?;
+        let mut wr_c2o = wr.open_table(*ORIGINAL_URL)?;
+        let mut wr_c2t = wr.open_table(*CREATED)?;
+        let mut wr_tags = wr.open_table(*TAGS)?;
+        let mut wr_status = wr.open_table(*STATUS)?;
+        let mut wr_c2ip = wr.open_table(*CODE_TO_CREATOR_IP)?;
+        let mut wr_ip2n = wr.open_table(*CREATOR_IP_TO_COUNT)?;
+        let mut wr_deleted = wr.open_table(*DELETED)?;
+
+        match wr_c2u.remove(code)?.map(|url| url.value().to_string()) {
+            Some(url) => {
+                wr_u2c.remove(url.as_str())?;
+                wr_c2o.remove(code)?;
+                wr_c2t.remove(code)?;
+                wr_tags.remove(code)?;
+                wr_status.remove(code)?;
+                release_creator_quota(&mut wr_c2ip, &mut wr_ip2n, code)?;
+                wr_deleted.insert(code, url.as_str())?;
+                println!("  removed {} -> {}", code, url);
+                true
+            }
+            None => false,
+        }
+    };
+    wr.commit()?;
+
+    if removed && let Some(mirror) = &state.mirror {
+        mirror.removed(code);
+    }
+
+    Ok(removed)
+}
+
+/// `list_codes()` for startup scripts: every mapping, as `#{code, url}` maps.
+fn script_list_codes(state: &AppState) -> Result<rhai::Array> {
+    let rd = state.db()?.begin_read()?;
+    let rd_c2u = rd.open_table(*CODE_TO_URL)?;
+
+    let mut codes = rhai::Array::new();
+    for res in rd_c2u.iter()? {
+        let (code, url) = res?;
+        let mut map = rhai::Map::new();
+        map.insert("code".into(), code.value().to_string().into());
+        map.insert("url".into(), url.value().to_string().into());
+        codes.push(map.into());
+    }
+
+    Ok(codes)
+}
+
+/// Prometheus text format for `--metrics-port`, one series per database (labelled by namespace, `""` for the main one).
+fn render_metrics(states: &[(String, Arc<AppState>)], histograms: &[Arc<LatencyHistogram>], started: Instant) -> String {
+    let mut out = String::new();
+    out += "# HELP cc_uptime_seconds Seconds since the server started.\n# TYPE cc_uptime_seconds gauge\n";
+    out += &format!("cc_uptime_seconds {}\n", started.elapsed().as_secs());
+
+    out += "# HELP cc_mappings Code -> url mappings in the database.\n# TYPE cc_mappings gauge\n";
+    for (name, state) in states {
+        if let Ok((codes, _)) = state.db().and_then(|db| check_db(&db)) {
+            out += &format!("cc_mappings{{namespace=\"{}\"}} {}\n", name, codes);
+        }
+    }
+
+    out += "# HELP cc_redirects_total Codes looked up successfully.\n# TYPE cc_redirects_total counter\n";
+    for (name, state) in states {
+        out += &format!("cc_redirects_total{{namespace=\"{}\"}} {}\n", name, state.redirects.load(Ordering::Relaxed));
+    }
+
+    out += "# HELP cc_created_total Codes created by /put.\n# TYPE cc_created_total counter\n";
+    for (name, state) in states {
+        out += &format!("cc_created_total{{namespace=\"{}\"}} {}\n", name, state.created.load(Ordering::Relaxed));
+    }
+
+    out += "# HELP cc_db_errors_total Requests that failed with a database error.\n# TYPE cc_db_errors_total counter\n";
+    for (name, state) in states {
+        out += &format!("cc_db_errors_total{{namespace=\"{}\"}} {}\n", name, state.db_errors.load(Ordering::Relaxed));
+    }
+
+    out += "# HELP cc_cache_lookups_total Redirect cache lookups, with --cache-size.\n# TYPE cc_cache_lookups_total counter\n";
+    for (name, state) in states {
+        if let Some(cache) = &state.cache {
+            out += &format!("cc_cache_lookups_total{{namespace=\"{}\",result=\"hit\"}} {}\n", name, cache.hits.load(Ordering::Relaxed));
+            out += &format!("cc_cache_lookups_total{{namespace=\"{}\",result=\"miss\"}} {}\n", name, cache.misses.load(Ordering::Relaxed));
+        }
+    }
+
+    for histogram in histograms {
+        out += &histogram.render();
+    }
+
+    out
+}
+
+/// Reads `--code-reserved`: one code per line, ignoring blank lines and `#` comments.
+fn load_reserved_codes(path: &std::path::Path) -> Result<HashSet<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_ascii_lowercase)
+        .collect())
+}
+
+/// Reloads `--code-reserved` whenever the server gets SIGHUP, keeping the old list if it can't be read.
+/// `--code-reserved` for the subcommands that generate codes, exiting if it can't be read.
+fn read_reserved_codes(path: Option<&std::path::Path>) -> HashSet<String> {
+    let Some(path) = path else { return HashSet::new() };
+    match load_reserved_codes(path) {
+        Ok(reserved) => reserved,
+        Err(e) => {
+            eprintln!("reserved codes file {} can't be read: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn spawn_reserved_reload(path: PathBuf, reserved: Arc<RwLock<HashSet<String>>>) {
+    tokio::spawn(async move {
+        let mut hup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+            Ok(sig) => sig,
+            Err(e) => {
+                println!("can't listen for SIGHUP, reserved codes won't be reloaded: {}", e);
+                return;
+            }
+        };
+
+        while hup.recv().await.is_some() {
+            match load_reserved_codes(&path) {
+                Ok(codes) => {
+                    println!("reloaded {} reserved codes from {}", codes.len(), path.display());
+                    *reserved.write().unwrap() = codes;
+                }
+                Err(e) => println!("couldn't reload reserved codes from {}: {}", path.display(), e),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn spawn_reserved_reload(_path: PathBuf, _reserved: Arc<RwLock<HashSet<String>>>) {}
+
+/// Writes the counters to `--metrics-file` every `interval_secs`.
+fn spawn_metrics_file(states: Vec<(String, Arc<AppState>)>, path: PathBuf, interval_secs: u64) {
+    let started = Instant::now();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            if let Err(e) = write_metrics_file(&states, &path, started) {
+                println!("can't write metrics to {}: {}", path.display(), e);
+            }
+        }
+    });
+}
+
+fn write_metrics_file(states: &[(String, Arc<AppState>)], path: &std::path::Path, started: Instant) -> Result<()> {
+    let databases = states.iter().map(|(name, state)| DatabaseMetrics {
+        namespace: name.clone(),
+        mappings: state.db().and_then(|db| check_db(&db)).ok().map(|(codes, _)| codes),
+        redirects: state.redirects.load(Ordering::Relaxed),
+        created: state.created.load(Ordering::Relaxed),
+        db_errors: state.db_errors.load(Ordering::Relaxed),
+        cache_hits: state.cache.as_ref().map(|cache| cache.hits.load(Ordering::Relaxed)),
+        cache_misses: state.cache.as_ref().map(|cache| cache.misses.load(Ordering::Relaxed)),
+    }).collect();
+    let snapshot = MetricsSnapshot { uptime_seconds: started.elapsed().as_secs(), databases };
+
+    // write next to it and rename, so readers never see half a file
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, serde_json::to_vec(&snapshot)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Prints some counters for each database whenever the server gets SIGUSR1.
+#[cfg(unix)]
+fn spawn_stats_dump(states: Vec<(String, Arc<AppState>)>) {
+    let started = Instant::now();
+    tokio::spawn(async move {
+        let mut usr1 = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1()) {
+            Ok(sig) => sig,
+            Err(e) => {
+                println!("can't listen for SIGUSR1, no stats dumps: {}", e);
+                return;
+            }
+        };
+
+        while usr1.recv().await.is_some() {
+            println!("stats: up {}s", started.elapsed().as_secs());
+            for (name, state) in &states {
+                let label = if name.is_empty() { "/".to_string() } else { format!("/{}/", name) };
+                let mappings = match state.db().and_then(|db| check_db(&db)) {
+                    Ok((codes, _)) => codes.to_string(),
+                    Err(e) => format!("unknown ({})", e),
+                };
+                let cache = match &state.cache {
+                    Some(cache) => {
+                        let (hits, misses) = (cache.hits.load(Ordering::Relaxed), cache.misses.load(Ordering::Relaxed));
+                        let rate = if hits + misses == 0 { 0.0 } else { hits as f64 / (hits + misses) as f64 * 100.0 };
+                        format!("{:.1}% ({} hits, {} misses)", rate, hits, misses)
+                    }
+                    None => "off".to_string(),
+                };
+                println!("  {}: {} mappings, {} redirects, cache {}",
+                         label, mappings, state.redirects.load(Ordering::Relaxed), cache);
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn spawn_stats_dump(_states: Vec<(String, Arc<AppState>)>) {}
+
+fn parse_request_id_length(s: &str) -> Result<usize, String> {
+    match s.parse() {
+        Ok(len @ (8 | 16 | 24 | 32 | 64)) => Ok(len),
+        _ => Err("expected 8, 16, 24, 32 or 64".to_string()),
+    }
+}
+
+fn parse_latency_buckets(s: &str) -> Result<LatencyBuckets, String> {
+    let buckets = s.split(',')
+        .map(|b| b.trim().parse::<f64>().map_err(|_| format!("not a number: {}", b.trim())))
+        .collect::<Result<Vec<_>, _>>()?;
+    if buckets.iter().any(|b| !b.is_finite() || *b <= 0.0) {
+        return Err("buckets must be positive".to_string());
+    }
+    if buckets.windows(2).any(|w| w[0] >= w[1]) {
+        return Err("buckets must be strictly increasing".to_string());
+    }
+    Ok(LatencyBuckets(buckets))
+}
+
+fn parse_sampling_rate(s: &str) -> Result<f64, String> {
+    match s.parse() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err("expected a number from 0 to 1".to_string()),
+    }
+}
+
+fn parse_namespace(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => Ok((name.to_string(), PathBuf::from(path))),
+        _ => Err("expected NAME=PATH".to_string()),
+    }
+}
+
+/// Opens a table for reading, or `None` if the database is from before it existed.
+fn open_optional<K: Key + 'static, V: Value + 'static>(
+    rd: &ReadTransaction,
+    table: TableDefinition<K, V>
+) -> Result<Option<ReadOnlyTable<K, V>>> {
+    match rd.open_table(table) {
+        Ok(tb) => Ok(Some(tb)),
+        Err(TableError::TableDoesNotExist(_)) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Gives a removed code back to the quota of the ip that created it, if it was recorded.
+fn release_creator_quota(
+    c2ip: &mut redb::Table<&'static str, &'static str>,
+    ip2n: &mut redb::Table<&'static str, u64>,
+    code: &str
+) -> Result<(), StorageError> {
+    let Some(ip) = c2ip.remove(code)?.map(|ip| ip.value().to_string()) else {
+        return Ok(());
+    };
+    let count = ip2n.get(ip.as_str())?.map(|n| n.value());
+    match count {
+        Some(n) if n > 1 => ip2n.insert(ip.as_str(), n - 1)?,
+        _ => ip2n.remove(ip.as_str())?,
+    };
+    Ok(())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn table_names(db: &Database) -> Result<Vec<String>> {
+    Ok(db.begin_read()?.list_tables()?.map(|tb| tb.name().to_string()).collect())
+}
+
+/// Creates any tables that don't exist yet, so handlers can open them for reading.
+fn init_tables(db: &Database) -> Result<()> {
+    let names = [CODE_TO_URL.name(), URL_TO_CODE.name(), ORIGINAL_URL.name(), CREATED.name(), TAGS.name(), STATUS.name(),
+        CODE_TO_CREATOR_IP.name(), CREATOR_IP_TO_COUNT.name(), DELETED.name()];
+    let existing = table_names(db)?;
+    if names.iter().all(|name| existing.iter().any(|e| e == name)) {
+        return Ok(());
+    }
+
+    let wr = db.begin_write()?;
+    wr.open_table(*CODE_TO_URL)?;
+    wr.open_table(*URL_TO_CODE)?;
+    wr.open_table(*ORIGINAL_URL)?;
+    wr.open_table(*CREATED)?;
+    wr.open_table(*TAGS)?;
+    wr.open_table(*STATUS)?;
+    wr.open_table(*CODE_TO_CREATOR_IP)?;
+    wr.open_table(*CREATOR_IP_TO_COUNT)?;
+    wr.open_table(*DELETED)?;
+    wr.commit()?;
+
+    Ok(())
+}
+
+/// Opens both tables and reads their lengths, to catch storage problems early.
+fn check_db(db: &Database) -> Result<(u64, u64)> {
+    let rd = db.begin_read()?;
+    let codes = rd.open_table(*CODE_TO_URL)?.len()?;
+    let urls = rd.open_table(*URL_TO_CODE)?.len()?;
+    Ok((codes, urls))
+}
+
+/// Runs a stored url through the same checks new urls get, for `audit`.
+fn check_target(url: &str, deny_empty_path_targets: bool) -> Result<(), String> {
+    let (uri, _) = normalize_url(url)?;
+    if !is_redirectable(url) {
+        return Err("not a valid redirect target".to_string());
+    }
+    if deny_empty_path_targets && matches!(uri.path(), "" | "/") {
+        return Err("url must have a path".to_string());
+    }
+    Ok(())
+}
+
+/// Whether a stored url is fine to put in a `Location` header: absolute, with an allowed scheme.
+/// Guards against entries from before validation existed, or edited by hand.
+fn is_redirectable(url: &str) -> bool {
+    if denied_scheme(url).is_some() {
+        return false;
+    }
+    if HeaderValue::from_str(url).is_err() {
+        return false;
+    }
+
+    match url.parse::<Uri>() {
+        Ok(uri) => uri.authority().is_some() && uri.scheme_str().is_some_and(|s| ALLOWED_SCHEMES.contains(&s.to_ascii_lowercase().as_str())),
+        Err(_) => false,
+    }
+}
+
+struct IndexPage {
+    html: String,
+    /// When the file was last modified, truncated to seconds.
+    modified: Option<SystemTime>,
+}
+
+/// Serves the index page with `ETag`/`Last-Modified`, answering matching conditional requests with 304.
+fn serve_index(index: &IndexPage, query: IndexQuery, headers: &HeaderMap) -> AxumResponse {
+    let host = headers.get(HOST).and_then(|h| h.to_str().ok());
+    let body = render_index(&index.html, query.created.as_deref(), host);
+
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+
+    // If-None-Match takes precedence over If-Modified-Since when both are sent
+    let not_modified = match headers.get(IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        Some(tags) => tags.split(',').map(str::trim).any(|t| t == "*" || t.trim_start_matches("W/") == etag),
+        None => {
+            let since = headers.get(IF_MODIFIED_SINCE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| httpdate::parse_http_date(v).ok());
+            matches!((index.modified, since), (Some(modified), Some(since)) if modified <= since)
+        }
+    };
+
+    let mut res = if not_modified {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        Html(body).into_response()
+    };
+
+    if let Ok(etag) = HeaderValue::from_str(&etag) {
+        res.headers_mut().insert(ETAG, etag);
+    }
+    if let Some(Ok(modified)) = index.modified.map(|m| HeaderValue::from_str(&httpdate::fmt_http_date(m))) {
+        res.headers_mut().insert(LAST_MODIFIED, modified);
+    }
+    res
+}
+
+/// Renders the index page, exposing a freshly created short url (from `?created=<code>`)
+/// as a `data-short-url` attribute on `<body>` so the page doesn't need to parse the query itself.
+fn render_index(index: &str, created: Option<&str>, host: Option<&str>) -> String {
+    let code = match created {
+        Some(code) if is_code(code) => code,
+        _ => return index.to_string(),
+    };
+
+    let Some(pos) = index.find("<body") else {
+        return index.to_string();
+    };
+
+    // protocol-relative, since we don't know if we're behind tls
+    let short_url = match host {
+        Some(host) => format!("//{}/{}", host, code),
+        None => format!("/{}", code),
+    };
+
+    let at = pos + "<body".len();
+    format!("{} data-short-url=\"{}\"{}", &index[..at], escape_html(&short_url), &index[at..])
+}
+
+/// Collapses duplicate leading slashes and trims a single trailing dot, for `--fix-malformed-codes`.
+/// Only returns something if the path changed and is then a plain `/code` or `/namespace/code`.
+fn fix_code_path(path: &str) -> Option<String> {
+    let trimmed = path.trim_start_matches('/');
+    let trimmed = match trimmed.strip_suffix('.') {
+        Some(rest) if !rest.ends_with('.') => rest,
+        _ => trimmed,
+    };
+
+    let fixed = format!("/{}", trimmed);
+    if fixed == path {
+        return None;
+    }
+
+    let segments = trimmed.split('/').collect::<Vec<_>>();
+    if segments.len() > 2 || !segments.iter().all(|s| is_code(s)) {
+        return None;
+    }
+    Some(fixed)
+}
+
+/// For `--path-normalization`, the code in a `/{code}{suffix}` path.
+fn strip_path_suffix(path: &str, suffixes: &[String]) -> Option<String> {
+    let code = suffixes.iter()
+        .find_map(|suffix| path.strip_suffix(suffix.as_str()))?
+        .strip_prefix('/')?;
+    is_code(code).then(|| code.to_string())
+}
+
+fn is_code(s: &str) -> bool {
+    !s.is_empty() && s.len() <= MAX_CODE_LEN && s.chars().all(is_code_char)
+}
+
+fn is_code_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+macro_rules! nope {
+    ($state:expr, $e:expr) => {
+        {
+            println!("db error: {}", $e);
+            $state.db_error();
+            let j = Json(Response { ok: false, msg: "problem with database".to_string() });
+            return (StatusCode::INTERNAL_SERVER_ERROR, j).into_response();
+        }
+    };
+    ($state:expr, $e:expr, $id:expr) => {
+        {
+            println!("db error: {} (id {})", $e, $id);
+            $state.db_error();
+            let j = Json(Response { ok: false, msg: "problem with database".to_string() });
+            return (StatusCode::INTERNAL_SERVER_ERROR, j).into_response();
+        }
+    };
+}
+
+/// The database, or a 503 while `--db-reopen-on-error` is reopening it.
+macro_rules! db {
+    ($state:expr) => {
+        match $state.db.load_full() {
+            Some(db) => db,
+            None => {
+                let j = Json(Response { ok: false, msg: "database is being reopened".to_string() });
+                return (StatusCode::SERVICE_UNAVAILABLE, j).into_response();
+            }
+        }
+    };
+}
+
+async fn health(State(state): State<Arc<AppState>>) -> AxumResponse {
+    if !state.db_healthy.load(Ordering::Relaxed) {
+        let j = Json(Response { ok: false, msg: "last database check failed".to_string() });
+        return (StatusCode::SERVICE_UNAVAILABLE, j).into_response();
+    }
+
+    // the standard tables are always checked, extra ones just need to exist
+    let missing = match state.db().and_then(|db| check_db(&db).and_then(|_| table_names(&db))) {
+        Ok(names) => state.health_tables.iter().find(|t| !names.contains(t)),
+        Err(e) => {
+            println!("db error: {}", e);
+            let j = Json(Response { ok: false, msg: "problem with database".to_string() });
+            return (StatusCode::SERVICE_UNAVAILABLE, j).into_response();
+        }
+    };
+
+    match missing {
+        Some(table) => {
+            let j = Json(Response { ok: false, msg: format!("table {} not found", table) });
+            (StatusCode::SERVICE_UNAVAILABLE, j).into_response()
+        }
+        None => Json(Response { ok: true, msg: "ok".to_string() }).into_response(),
+    }
+}
+
+async fn status(State(state): State<Arc<AppState>>) -> AxumResponse {
+    Json(StatusResponse {
+        mappings: state.status_mappings.load(Ordering::Relaxed),
+        uptime_secs: state.started.elapsed().as_secs(),
+        version: env!("CARGO_PKG_VERSION"),
+    }).into_response()
+}
+
+async fn get_code(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(id)): Extension<RequestId>,
+    code: Path<String>
+) -> AxumResponse {
+    if let Some(limit) = &state.redirect_limit
+        && let Err(wait) = limit.lock().unwrap().take()
+    {
+        // Retry-After is in whole seconds, and waits under a second would round down to "now"
+        let retry = wait.as_secs_f64().ceil().max(1.0) as u64;
+        let j = Json(Response { ok: false, msg: "too many requests".to_string() });
+        return (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, retry.to_string())], j).into_response();
+    }
+
+    if let Some((url, permanent)) = state.cache.as_ref().and_then(|cache| cache.get(&code)) {
+        println!("found code {} -> {} (cached, id {})", code.as_str(), url, id);
+        return found(&state, &code, url, permanent);
+    }
+
+    let rd = match db!(state).begin_read() {
+        Ok(rd) => rd,
+        Err(e) => nope!(state, e, id),
+    };
+
+    let rd_c2u = match rd.open_table(*CODE_TO_URL) {
+        Ok(tb) => tb,
+        Err(e) => nope!(state, e, id)
+    };
+
+    let rd_c2o = match rd.open_table(*ORIGINAL_URL) {
+        Ok(tb) => tb,
+        Err(e) => nope!(state, e, id)
+    };
+
+    let url = match rd_c2u.get(code.as_str()) {
+        Ok(Some(url)) => url.value().to_string(),
+        Ok(None) => {
+            let rd_deleted = match rd.open_table(*DELETED) {
+                Ok(tb) => tb,
+                Err(e) => nope!(state, e, id)
+            };
+
+            return match rd_deleted.get(code.as_str()) {
+                Ok(Some(_)) => (StatusCode::GONE, Html(GONE_PAGE)).into_response(),
+                Ok(None) if state.suggest => {
+                    let suggestions = match suggest_codes(&rd_c2u, &code) {
+                        Ok(suggestions) => suggestions,
+                        Err(e) => nope!(state, e, id)
+                    };
+                    (StatusCode::NOT_FOUND, Html(not_found_page(&suggestions))).into_response()
+                }
+                Ok(None) => StatusCode::NOT_FOUND.into_response(),
+                Err(e) => nope!(state, e, id)
+            };
+        }
+        Err(e) => nope!(state, e, id)
+    };
+
+    // prefer the url exactly as it was submitted, if it was kept
+    let url = match rd_c2o.get(code.as_str()) {
+        Ok(Some(original)) => original.value().to_string(),
+        Ok(None) => url,
+        Err(e) => nope!(state, e, id)
+    };
+
+    let rd_status = match rd.open_table(*STATUS) {
+        Ok(tb) => tb,
+        Err(e) => nope!(state, e, id)
+    };
+
+    let permanent = match rd_status.get(code.as_str()) {
+        Ok(permanent) => permanent.map(|p| p.value()),
+        Err(e) => nope!(state, e, id)
+    };
+
+    if !is_redirectable(&url) {
+        println!("refusing to redirect {} to malformed url: {}", code.as_str(), url);
+        let j = Json(Response { ok: false, msg: "stored url is invalid".to_string() });
+        return (StatusCode::INTERNAL_SERVER_ERROR, j).into_response();
+    }
+
+    if let Some(cache) = &state.cache {
+        cache.insert(&code, &url, permanent);
+    }
+
+    println!("found code {} -> {} (id {})", code.as_str(), url, id);
+    found(&state, &code, url, permanent)
+}
+
+/// Redirects to a code's url, or with `--disable-redirect` just sends it.
+/// `permanent` is the code's own status, if it has one.
+fn found(state: &AppState, code: &str, url: String, permanent: Option<bool>) -> AxumResponse {
+    state.redirects.fetch_add(1, Ordering::Relaxed);
+    if state.disable_redirect {
+        return Json(CodeResponse { ok: true, url, code: code.to_string() }).into_response();
+    }
+    if state.preview_delay > 0 {
+        return Html(interstitial_page(&url, state.preview_delay)).into_response();
+    }
+    if permanent.unwrap_or(true) {
+        Redirect::permanent(&url).into_response()
+    } else {
+        Redirect::temporary(&url).into_response()
+    }
+}
+
+/// Re-serializes a json body with `indent` spaces, for `--json-response-indent`.
+fn indent_json(body: &[u8], indent: usize) -> Option<Vec<u8>> {
+    let value = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+    let indent = b" ".repeat(indent);
+    let mut out = Vec::with_capacity(body.len() * 2);
+    let mut ser = serde_json::Serializer::with_formatter(&mut out, serde_json::ser::PrettyFormatter::with_indent(&indent));
+    value.serialize(&mut ser).ok()?;
+    out.push(b'\n');
+    Some(out)
+}
+
+/// Codes one edit (an insertion, deletion or change of a character) away from `code`, for `--suggest`.
+fn suggest_codes(rd_c2u: &ReadOnlyTable<&str, &str>, code: &str) -> Result<Vec<String>, StorageError> {
+    let mut suggestions = Vec::new();
+    for res in rd_c2u.iter()?.take(MAX_SUGGEST_SCAN) {
+        let (candidate, _) = res?;
+        if within_one_edit(code, candidate.value()) {
+            suggestions.push(candidate.value().to_string());
+            if suggestions.len() == MAX_SUGGESTIONS {
+                break;
+            }
+        }
+    }
+    Ok(suggestions)
+}
+
+fn within_one_edit(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if long.len() - short.len() > 1 {
+        return false;
+    }
+
+    let prefix = short.iter().zip(long).take_while(|(x, y)| x == y).count();
+    if short.len() == long.len() {
+        // one changed character, the rest the same
+        short[prefix..].len() <= 1 || short[prefix + 1..] == long[prefix + 1..]
+    } else {
+        // one extra character in the longer one
+        short[prefix..] == long[prefix + 1..]
+    }
+}
+
+fn not_found_page(suggestions: &[String]) -> String {
+    let mut page = "<!DOCTYPE html><html lang=en><head><meta charset=UTF-8><title>cc</title></head><body>\
+        <p>this link doesn't exist.</p>".to_string();
+    if !suggestions.is_empty() {
+        page += "<p>did you mean:</p><ul>";
+        for code in suggestions {
+            // relative, so it works under a namespace too
+            page += &format!("<li><a href=\"{0}\">{0}</a></li>", escape_html(code));
+        }
+        page += "</ul>";
+    }
+    page + "</body></html>"
+}
+
+/// Shows the target of a link and goes there after `delay` seconds, for `--preview-delay`.
+fn interstitial_page(url: &str, delay: u64) -> String {
+    let url = escape_html(url);
+    format!(
+        "<!DOCTYPE html><html lang=en><head><meta charset=UTF-8><title>cc</title>\
+        <meta http-equiv=refresh content=\"{delay};url={url}\"></head>\
+        <body><p>this link goes to <code>{url}</code>. you'll be taken there in {delay} seconds.</p>\
+        <p><a href=\"{url}\">continue</a></p></body></html>"
+    )
+}
+
+async fn codes_exist(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(id)): Extension<RequestId>,
+    Json(codes): Json<Vec<String>>
+) -> AxumResponse {
+    if codes.len() > MAX_EXISTS_CODES {
+        let msg = format!("too many codes (at most {})", MAX_EXISTS_CODES);
+        return (StatusCode::BAD_REQUEST, Json(Response { ok: false, msg })).into_response();
+    }
+    if let Some(code) = codes.iter().find(|c| !is_code(c)) {
+        let msg = format!("invalid code: {}", code);
+        return (StatusCode::BAD_REQUEST, Json(Response { ok: false, msg })).into_response();
+    }
+
+    // each code is a lookup, so this would otherwise be a way around --max-redirects-per-sec
+    if let Some(limit) = &state.redirect_limit
+        && let Err(wait) = limit.lock().unwrap().take_n(codes.len())
+    {
+        let retry = wait.as_secs_f64().ceil().max(1.0) as u64;
+        let j = Json(Response { ok: false, msg: "too many requests".to_string() });
+        return (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, retry.to_string())], j).into_response();
+    }
+
+    let rd = match db!(state).begin_read() {
+        Ok(rd) => rd,
+        Err(e) => nope!(state, e, id),
+    };
+
+    let rd_c2u = match rd.open_table(*CODE_TO_URL) {
+        Ok(tb) => tb,
+        Err(e) => nope!(state, e, id)
+    };
+
+    let (mut present, mut missing) = (Vec::new(), Vec::new());
+    for code in codes {
+        match rd_c2u.get(code.as_str()) {
+            Ok(Some(_)) => present.push(code),
+            Ok(None) => missing.push(code),
+            Err(e) => nope!(state, e, id)
+        }
+    }
+
+    Json(ExistsResponse { present, missing }).into_response()
+}
+
+async fn preview_card(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(id)): Extension<RequestId>,
+    code: Path<String>
+) -> AxumResponse {
+    let Some(preview) = &state.preview else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let cached = preview.rendered.lock().unwrap().get(code.as_str()).cloned();
+    let png = match cached {
+        Some(png) => png,
+        None => {
+            let rd = match db!(state).begin_read() {
+                Ok(rd) => rd,
+                Err(e) => nope!(state, e, id),
+            };
+
+            let rd_c2u = match rd.open_table(*CODE_TO_URL) {
+                Ok(tb) => tb,
+                Err(e) => nope!(state, e, id)
+            };
+
+            let url = match rd_c2u.get(code.as_str()) {
+                Ok(Some(url)) => url.value().to_string(),
+                Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+                Err(e) => nope!(state, e, id)
+            };
+
+            let host = url.parse::<Uri>().ok()
+                .and_then(|u| u.host().map(str::to_string))
+                .unwrap_or_default();
+            let png = match render_card(&preview.font, &code, &host) {
+                Ok(png) => Bytes::from(png),
+                Err(e) => {
+                    println!("couldn't render preview for {}: {}", code.as_str(), e);
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+            };
+
+            let mut rendered = preview.rendered.lock().unwrap();
+            if rendered.len() >= MAX_PREVIEW_CARDS {
+                rendered.clear();
+            }
+            rendered.insert(code.to_string(), png.clone());
+            png
+        }
+    };
+
+    ([(CONTENT_TYPE, "image/png")], png).into_response()
+}
+
+/// Draws a 1200x630 (the usual open graph size) card with the code and the host it goes to.
+fn render_card(font: &ab_glyph::FontArc, code: &str, host: &str) -> Result<Vec<u8>> {
+    const WIDTH: u32 = 1200;
+    const HEIGHT: u32 = 630;
+    let mut img = image::RgbImage::from_pixel(WIDTH, HEIGHT, image::Rgb([24, 24, 27]));
+
+    let mut draw_centered = |text: &str, size: f32, y: i32, color: [u8; 3]| {
+        // shrink long text to fit
+        let (w, _) = imageproc::drawing::text_size(size, font, text);
+        let size = if w > WIDTH - 100 { size * (WIDTH - 100) as f32 / w as f32 } else { size };
+        let (w, _) = imageproc::drawing::text_size(size, font, text);
+        let x = (WIDTH.saturating_sub(w) / 2) as i32;
+        imageproc::drawing::draw_text_mut(&mut img, image::Rgb(color), x, y, size, font, text);
+    };
+    draw_centered(&format!("/{}", code), 140.0, 200, [250, 250, 250]);
+    draw_centered(&format!("→ {}", host), 56.0, 390, [161, 161, 170]);
+
+    let mut png = Vec::new();
+    image::DynamicImage::ImageRgb8(img).write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)?;
+    Ok(png)
+}
+
+async fn put_new(
+    State(state): State<Arc<AppState>>,
+    Extension(RequestId(id)): Extension<RequestId>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    OriginalUri(uri): OriginalUri,
+    Query(query): Query<PutQuery>,
+    raw_url: Bytes
+) -> AxumResponse {
+    let original = match std::str::from_utf8(&raw_url) {
+        Ok(u) => u.trim(),
+        Err(e) => {
+            let msg = format!("invalid utf-8 in url: {}", e);
+            return reject(&state, StatusCode::BAD_REQUEST, msg, &String::from_utf8_lossy(&raw_url));
+        }
+    };
+
+    // checked before resolving, which would quietly percent-encode them
+    if let Err(msg) = check_url_chars(original) {
+        return reject(&state, StatusCode::BAD_REQUEST, msg, original);
+    }
+    let original = resolve_relative(state.relative_base.as_ref(), original);
+
+    let (url, str_url) = match normalize_url(&original) {
+        Ok(u) => u,
+        Err(msg) => return reject(&state, StatusCode::BAD_REQUEST, msg, &original),
+    };
+    // still normalized above, to validate it
+    let str_url = if state.raw_urls { original.clone() } else { str_url };
+
+    let tags = match query.tags.as_deref().map(parse_tags) {
+        Some(Ok(tags)) => tags,
+        Some(Err(msg)) => return (StatusCode::BAD_REQUEST, Json(Response { ok: false, msg })).into_response(),
+        None => Vec::new(),
+    };
+
+    if query.check_only {
+        let rd = match db!(state).begin_read() {
+            Ok(rd) => rd,
+            Err(e) => nope!(state, e, id),
+        };
+
+        let rd_u2c = match rd.open_table(*URL_TO_CODE) {
+            Ok(tb) => tb,
+            Err(e) => nope!(state, e, id),
+        };
+
+        return match rd_u2c.get(str_url.as_str()) {
+            Ok(Some(code)) => Json(Response { ok: true, msg: code.value().to_string() }).into_response(),
+            Ok(None) => {
+                let j = Json(Response { ok: false, msg: "not found".to_string() });
+                (StatusCode::NOT_FOUND, j).into_response()
+            }
+            Err(e) => nope!(state, e, id),
+        };
+    }
+
+    if state.validate_utf8_strict && let Err(msg) = check_utf8_strict(&url) {
+        return reject(&state, StatusCode::BAD_REQUEST, msg, &original);
+    }
+
+    if state.deny_empty_path_targets && matches!(url.path(), "" | "/") {
+        return reject(&state, StatusCode::BAD_REQUEST, "url must have a path".to_string(), &original);
+    }
+
+    if let Some(base) = &state.base_url {
+        let db = db!(state);
+        match follow_chain(&state, &db, base, &str_url) {
+            Ok(Chain::Ends) => {}
+            Ok(Chain::Cycle) => {
+                return reject(&state, StatusCode::BAD_REQUEST, "redirect cycle detected".to_string(), &original);
+            }
+            Ok(Chain::TooLong) => {
+                println!("warning: {} leads through more than {} short links", str_url, state.redirect_loop_max);
+            }
+            Err(e) => nope!(state, e, id),
+        }
+    }
+
+    // after following the chain, so a cycle is reported as one
+    if let Ok(target) = url::Url::parse(&str_url)
+        && let Some(host) = target.host_str()
+        && let Some(port) = target.port_or_known_default()
+        && state.self_hosts.contains(&(host.to_string(), port))
+    {
+        return reject(&state, StatusCode::BAD_REQUEST, "url points back at this server".to_string(), &original);
+    }
+
+    if let Some(client) = &state.target_client {
+        let target = if state.keep_original { &original } else { &str_url };
+        let msg = match client.head(target.as_str()).send().await {
+            Ok(res) if res.status().is_client_error() || res.status().is_server_error() => {
+                format!("target url returned HTTP {}", res.status().as_u16())
+            }
+            Ok(_) => String::new(),
+            Err(e) if e.is_timeout() => "target url timed out".to_string(),
+            Err(e) => format!("could not reach target url: {}", e),
+        };
+
+        if !msg.is_empty() {
+            return reject(&state, StatusCode::UNPROCESSABLE_ENTITY, msg, &original);
+        }
+    }
+
+    // begin_write blocks until any other write finishes, so wait for it off the async runtime
+    // (if this gives up, the transaction is dropped, and so aborted, whenever it does start)
+    let db = db!(state);
+    let begin = tokio::task::spawn_blocking(move || db.begin_write());
+    let mut wr = match tokio::time::timeout(state.write_timeout, begin).await {
+        Ok(Ok(Ok(wr))) => wr,
+        Ok(Ok(Err(e))) => nope!(state, e, id),
+        Ok(Err(e)) => nope!(state, e, id),
+        Err(_) => {
+            println!("write lock timeout: {} after {}ms", uri.path(), state.write_timeout.as_millis());
+            let j = Json(Response { ok: false, msg: "write lock timeout".to_string() });
+            return (StatusCode::SERVICE_UNAVAILABLE, j).into_response();
+        }
+    };
+
+    if let Err(e) = wr.set_durability(state.durability.into()) {
+        nope!(state, e, id)
     }
 
-    let wr = match db.begin_write() {
-        Ok(wr) => wr,
-        Err(e) => nope!(e),
+    let mut wr_u2c = match wr.open_table(*URL_TO_CODE) {
+        Ok(tb) => tb,
+        Err(e) => nope!(state, e, id),
     };
 
-    let mut wr_u2c = match wr.open_table(URL_TO_CODE) {
+    let mut wr_c2u = match wr.open_table(*CODE_TO_URL) {
         Ok(tb) => tb,
-        Err(e) => nope!(e),
+        Err(e) => nope!(state, e, id),
     };
 
-    let mut wr_c2u = match wr.open_table(CODE_TO_URL) {
+    let wr_deleted = match wr.open_table(*DELETED) {
         Ok(tb) => tb,
-        Err(e) => nope!(e),
+        Err(e) => nope!(state, e, id),
     };
-    match wr_u2c.get(str_url.as_str()) {
+    match stored_code(&wr_u2c, &str_url) {
         Ok(Some(code)) => {
-            let code = code.value().to_string();
-            return Json(Response { ok: true, msg: code }).into_response();
+            // the code may be shared, so its status isn't changed out from under whoever made it
+            if let Some(permanent) = query.permanent {
+                let wr_status = match wr.open_table(*STATUS) {
+                    Ok(tb) => tb,
+                    Err(e) => nope!(state, e, id),
+                };
+
+                let current = match wr_status.get(code.as_str()) {
+                    Ok(current) => current.map(|p| p.value()).unwrap_or(true),
+                    Err(e) => nope!(state, e, id),
+                };
+
+                if current != permanent {
+                    let msg = format!("url already has a code ({}), and /put can't change whether it's permanent", code);
+                    return (StatusCode::CONFLICT, Json(Response { ok: false, msg })).into_response();
+                }
+            }
+
+            // tags are only ever added to, so they're merged into the existing code's
+            if !tags.is_empty() {
+                let mut wr_tags = match wr.open_table(*TAGS) {
+                    Ok(tb) => tb,
+                    Err(e) => nope!(state, e, id),
+                };
+
+                let existing = match wr_tags.get(code.as_str()) {
+                    Ok(existing) => existing.map(|t| t.value().to_string()).unwrap_or_default(),
+                    Err(e) => nope!(state, e, id),
+                };
+
+                let merged = match parse_tags(&format!("{},{}", existing, tags.join(","))) {
+                    Ok(merged) => merged,
+                    Err(msg) => return (StatusCode::BAD_REQUEST, Json(Response { ok: false, msg })).into_response(),
+                };
+
+                if let Err(e) = wr_tags.insert(code.as_str(), merged.join(",").as_str()) {
+                    nope!(state, e, id)
+                }
+
+                drop(wr_tags);
+                drop(wr_u2c);
+                drop(wr_c2u);
+                drop(wr_deleted);
+                if let Err(e) = wr.commit() {
+                    nope!(state, e, id)
+                }
+            }
+
+            let j = Json(PutResponse { ok: true, msg: code, created: false });
+            return (put_status(state.put_status, false), j).into_response();
         }
         Ok(None) => {}
-        Err(e) => nope!(e),
+        Err(e) => nope!(state, e, id),
+    }
+
+    // dedup hits above don't count, since they don't use up any codes
+    let client_ip = client.ip().to_string();
+    if state.max_codes_per_ip > 0 {
+        let wr_ip2n = match wr.open_table(*CREATOR_IP_TO_COUNT) {
+            Ok(tb) => tb,
+            Err(e) => nope!(state, e, id),
+        };
+
+        let count = match wr_ip2n.get(client_ip.as_str()) {
+            Ok(count) => count.map(|n| n.value()).unwrap_or(0),
+            Err(e) => nope!(state, e, id),
+        };
+        drop(wr_ip2n);
+
+        if count >= state.max_codes_per_ip {
+            let msg = format!("too many codes created from this address (at most {})", state.max_codes_per_ip);
+            return (StatusCode::TOO_MANY_REQUESTS, Json(Response { ok: false, msg })).into_response();
+        }
     }
 
     // make sure code is unique
-    let mut code = gen_key();
-    loop {
-        // this may overwrite something in the astronomically small case that
-        // another writer inserts the same code after this and before the commit
-        // but its fine lol
-        match wr_c2u.get(code.as_str()) {
-            Ok(None) => break,
-            Ok(Some(_)) => code = gen_key(),
-            Err(e) => nope!(e),
+    // this may overwrite something in the astronomically small case that
+    // another writer inserts the same code after this and before the commit
+    // but its fine lol
+    let code = match insert_url(&mut wr_c2u, &mut wr_u2c, &wr_deleted, &str_url, || state.new_code()) {
+        Ok(code) => code,
+        Err(e) => nope!(state, e, id),
+    };
+
+    let mut wr_c2t = match wr.open_table(*CREATED) {
+        Ok(tb) => tb,
+        Err(e) => nope!(state, e, id),
+    };
+
+    if let Err(e) = wr_c2t.insert(code.as_str(), now_millis()) {
+        nope!(state, e, id)
+    }
+
+    if state.max_codes_per_ip > 0 {
+        let mut wr_c2ip = match wr.open_table(*CODE_TO_CREATOR_IP) {
+            Ok(tb) => tb,
+            Err(e) => nope!(state, e, id),
+        };
+
+        let mut wr_ip2n = match wr.open_table(*CREATOR_IP_TO_COUNT) {
+            Ok(tb) => tb,
+            Err(e) => nope!(state, e, id),
+        };
+
+        let count = match wr_ip2n.get(client_ip.as_str()) {
+            Ok(count) => count.map(|n| n.value()).unwrap_or(0),
+            Err(e) => nope!(state, e, id),
+        };
+
+        if let Err(e) = wr_c2ip.insert(code.as_str(), client_ip.as_str()) {
+            nope!(state, e, id)
+        }
+
+        if let Err(e) = wr_ip2n.insert(client_ip.as_str(), count + 1) {
+            nope!(state, e, id)
+        }
+    }
+
+    if !tags.is_empty() {
+        let mut wr_tags = match wr.open_table(*TAGS) {
+            Ok(tb) => tb,
+            Err(e) => nope!(state, e, id),
+        };
+
+        if let Err(e) = wr_tags.insert(code.as_str(), tags.join(",").as_str()) {
+            nope!(state, e, id)
         }
     }
 
-    if let Err(e) = wr_c2u.insert(code.as_str(), str_url.as_str()) {
-        nope!(e)
+    if let Some(permanent) = query.permanent {
+        let mut wr_status = match wr.open_table(*STATUS) {
+            Ok(tb) => tb,
+            Err(e) => nope!(state, e, id),
+        };
+
+        if let Err(e) = wr_status.insert(code.as_str(), permanent) {
+            nope!(state, e, id)
+        }
     }
 
-    if let Err(e) = wr_u2c.insert(str_url.as_str(), code.as_str()) {
-        nope!(e)
+    if state.keep_original && original != str_url {
+        let mut wr_c2o = match wr.open_table(*ORIGINAL_URL) {
+            Ok(tb) => tb,
+            Err(e) => nope!(state, e, id),
+        };
+
+        if let Err(e) = wr_c2o.insert(code.as_str(), original.as_str()) {
+            nope!(state, e, id)
+        }
     }
 
     drop(wr_u2c);
     drop(wr_c2u);
+    drop(wr_c2t);
+    drop(wr_deleted);
 
     if let Err(e) = wr.commit() {
-        nope!(e)
+        nope!(state, e, id)
+    }
+
+    if let Some(mirror) = &state.mirror {
+        mirror.stored(&code, &str_url);
+    }
+
+    state.created.fetch_add(1, Ordering::Relaxed);
+    println!("stored: {} -> {} (id {})", code.as_str(), url, id);
+    let j = Json(PutResponse { ok: true, msg: code.to_string(), created: true }).into_response();
+    (put_status(state.put_status, true), [(LOCATION, format!("/{}", code))] , j).into_response()
+}
+
+/// The status `/put` succeeds with under `--put-status`, for a new code or an existing one.
+fn put_status(mode: PutStatus, created: bool) -> StatusCode {
+    match (mode, created) {
+        (PutStatus::Ok, _) | (PutStatus::Split, false) => StatusCode::OK,
+        (PutStatus::Created, _) | (PutStatus::Split, true) => StatusCode::CREATED,
+    }
+}
+
+/// Refuses a submitted url, logging it first with `--log-rejected-urls`.
+fn reject(state: &AppState, status: StatusCode, msg: String, submitted: &str) -> AxumResponse {
+    if state.log_rejected_urls {
+        println!("rejected url ({}): {}", msg, redact_url(submitted));
+    }
+    (status, Json(Response { ok: false, msg })).into_response()
+}
+
+/// With `--relative-base`, turns a schemeless relative url into an absolute one. Anything else is left alone.
+fn resolve_relative(base: Option<&url::Url>, raw: &str) -> String {
+    match base {
+        Some(base) if matches!(url::Url::parse(raw), Err(url::ParseError::RelativeUrlWithoutBase)) => {
+            base.join(raw).map(String::from).unwrap_or_else(|_| raw.to_string())
+        }
+        _ => raw.to_string(),
+    }
+}
+
+/// Splits comma-separated tags, dropping empty and repeated ones.
+fn parse_tags(raw: &str) -> Result<Vec<String>, String> {
+    let mut tags = Vec::<String>::new();
+    for tag in raw.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        if tag.chars().count() > MAX_TAG_LEN || tag.chars().any(char::is_control) {
+            return Err(format!("invalid tag (at most {} characters, no control characters): {}", MAX_TAG_LEN, tag));
+        }
+        if !tags.iter().any(|t| t == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+
+    if tags.len() > MAX_TAGS {
+        return Err(format!("too many tags (at most {})", MAX_TAGS));
+    }
+    Ok(tags)
+}
+
+/// Where following a url through this server's own short links ends up.
+enum Chain {
+    /// It reaches a url that isn't one of ours, or a code that doesn't exist.
+    Ends,
+    /// It comes back to a code it already went through.
+    Cycle,
+    /// It was still going after `--redirect-loop-max` links.
+    TooLong,
+}
+
+/// Follows `url` through the short links it points to, for `--redirect-loop-max`.
+fn follow_chain(state: &AppState, db: &Database, base: &url::Url, url: &str) -> Result<Chain, redb::Error> {
+    let rd = db.begin_read()?;
+    let rd_c2u = rd.open_table(*CODE_TO_URL)?;
+
+    let mut seen = HashSet::new();
+    let mut url = url.to_string();
+    for _ in 0..state.redirect_loop_max {
+        let Some(code) = code_in_url(base, &url) else {
+            return Ok(Chain::Ends);
+        };
+        if !seen.insert(code.clone()) {
+            return Ok(Chain::Cycle);
+        }
+        match rd_c2u.get(code.as_str())? {
+            Some(next) => url = next.value().to_string(),
+            None => return Ok(Chain::Ends),
+        }
+    }
+
+    Ok(if code_in_url(base, &url).is_some() { Chain::TooLong } else { Chain::Ends })
+}
+
+/// The code a url points to, if it's a short link on this server.
+fn code_in_url(base: &url::Url, url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    if url.host_str() != base.host_str() || url.port_or_known_default() != base.port_or_known_default() {
+        return None;
+    }
+    let code = url.path().strip_prefix(base.path().trim_end_matches('/'))?.strip_prefix('/')?;
+    is_code(code).then(|| code.to_string())
+}
+
+/// Hosts (as `url::Url` writes them) and ports that reach a server bound to `bind`, or at `base` (`--base-url`).
+/// Binding every interface also covers loopback, since that's how it's most easily reached by accident.
+fn self_hosts(bind: SocketAddr, base: Option<&url::Url>) -> HashSet<(String, u16)> {
+    let ip = bind.ip();
+    let mut hosts = vec![match ip {
+        std::net::IpAddr::V4(ip) => ip.to_string(),
+        std::net::IpAddr::V6(ip) => format!("[{}]", ip),
+    }];
+    if ip.is_unspecified() || ip.is_loopback() {
+        hosts.extend(["localhost", "127.0.0.1", "[::1]"].map(String::from));
+    }
+    let mut hosts = hosts.into_iter().map(|host| (host, bind.port())).collect::<HashSet<_>>();
+    if let Some(base) = base
+        && let Some(host) = base.host_str()
+        && let Some(port) = base.port_or_known_default()
+    {
+        hosts.insert((host.to_string(), port));
+    }
+    hosts
+}
+
+/// Headers as `name: value` pairs for logging, with credentials replaced by `[REDACTED]`.
+fn redact_headers(headers: &HeaderMap) -> String {
+    let pairs = headers.iter().map(|(name, value)| {
+        let value = if name == AUTHORIZATION || name == COOKIE {
+            "[REDACTED]".to_string()
+        } else {
+            escape_body(value.as_bytes())
+        };
+        format!("{}: {}", name, value)
+    });
+    format!("[{}]", pairs.collect::<Vec<_>>().join(", "))
+}
+
+/// The first `MAX_LOGGED_BODY_LEN` bytes of a body as text, with bytes that aren't utf-8 written as `\xNN`.
+fn escape_body(body: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in body[..body.len().min(MAX_LOGGED_BODY_LEN)].utf8_chunks() {
+        out.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    if body.len() > MAX_LOGGED_BODY_LEN {
+        out.push_str("...");
+    }
+    out
+}
+
+/// Cuts off the query and fragment (which tend to hold tokens) and caps the length, for logging.
+fn redact_url(url: &str) -> String {
+    let (base, rest) = match url.find(['?', '#']) {
+        Some(i) => (&url[..i], "[redacted]"),
+        None => (url, ""),
+    };
+
+    let mut logged = base.chars().take(MAX_LOGGED_URL_LEN).collect::<String>();
+    if logged.len() < base.len() {
+        logged.push_str("...");
+    }
+    if !rest.is_empty() {
+        logged.push_str(&url[base.len()..base.len() + 1]);
+        logged.push_str(rest);
+    }
+    logged
+}
+
+/// Refuses urls with whitespace (a space, tab or line break left in the middle) or control characters.
+/// Surrounding whitespace should already be trimmed.
+fn check_url_chars(url: &str) -> Result<(), String> {
+    if url.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err("url contains whitespace or control characters".to_string());
+    }
+    Ok(())
+}
+
+/// Checks what the host, path and query of `url` percent-decode to, for `--validate-utf8-strict`.
+fn check_utf8_strict(url: &Uri) -> Result<(), String> {
+    let parts = [("host", url.host().unwrap_or("")), ("path", url.path()), ("query", url.query().unwrap_or(""))];
+    let mut rejected = Vec::new();
+    for (name, part) in parts {
+        let decoded = percent_encoding::percent_decode_str(part).collect::<Vec<_>>();
+        for chunk in decoded.utf8_chunks() {
+            for c in chunk.valid().chars() {
+                let cp = c as u32;
+                // U+FDD0..U+FDEF, and the last two code points of every plane
+                if (0xFDD0..=0xFDEF).contains(&cp) || cp & 0xFFFE == 0xFFFE {
+                    rejected.push(format!("U+{:04X} (noncharacter) in {}", cp, name));
+                } else if name == "host" && matches!(cp, 0xE000..=0xF8FF | 0xF0000..=0xFFFFD | 0x100000..=0x10FFFD) {
+                    rejected.push(format!("U+{:04X} (private use) in host", cp));
+                }
+            }
+            if !chunk.invalid().is_empty() {
+                let bytes = chunk.invalid().iter().map(|b| format!("%{:02X}", b)).collect::<String>();
+                rejected.push(format!("{} (ill-formed utf-8) in {}", bytes, name));
+            }
+        }
+    }
+
+    if rejected.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("url contains rejected characters: {}", rejected.join(", ")))
+    }
+}
+
+/// Parses, normalizes and checks a submitted url, returning it parsed and as it should be stored.
+fn normalize_url(raw: &str) -> Result<(Uri, String), String> {
+    if let Some(scheme) = denied_scheme(raw) {
+        return Err(format!("forbidden url scheme: {}", scheme));
+    }
+
+    check_url_chars(raw)?;
+    let url: Uri = raw.parse().map_err(|e| format!("invalid url: {}", e))?;
+
+    // normalize the url
+    let normalized = match raw.split_once('#') {
+        Some((_, fragment)) => url.to_string() + "#" + fragment,
+        None => url.to_string(),
+    };
+
+    // schemes are case-insensitive, so `HTTPS://` and `https://` are stored the same
+    let (scheme, normalized) = match url.scheme_str() {
+        Some(scheme) => (scheme.to_ascii_lowercase(), scheme.to_ascii_lowercase() + &normalized[scheme.len()..]),
+        None => return Err("url missing scheme".to_string()),
+    };
+    if !ALLOWED_SCHEMES.contains(&scheme.as_str()) {
+        return Err(format!("unsupported url scheme: {}", scheme));
+    }
+    // `https:///path` can parse with an empty authority, and would redirect nowhere
+    if url.host().is_none_or(str::is_empty) {
+        return Err("url has no host".to_string());
+    }
+
+    Ok((url, normalized))
+}
+
+/// The scheme of `url` if it's in `DENY_SCHEMES`, checked on the raw string so it doesn't matter whether it parses.
+fn denied_scheme(url: &str) -> Option<String> {
+    let (scheme, _) = url.trim_start().split_once(':')?;
+    let scheme = scheme.trim().to_ascii_lowercase();
+    DENY_SCHEMES.contains(&scheme.as_str()).then_some(scheme)
+}
+
+/// Generates codes until one isn't taken, reserved for a route, or tombstoned.
+fn unused_code(
+    c2u: &impl ReadableTable<&'static str, &'static str>,
+    deleted: &impl ReadableTable<&'static str, &'static str>,
+    mut gen_code: impl FnMut() -> String,
+) -> Result<String, StorageError> {
+    loop {
+        let code = gen_code();
+        if !RESERVED_CODES.contains(&code.as_str())
+            && c2u.get(code.as_str())?.is_none()
+            && deleted.get(code.as_str())?.is_none() {
+            return Ok(code);
+        }
+    }
+}
+
+/// The code `url` is already stored under, which `/put` hands back instead of making another.
+fn stored_code(u2c: &impl ReadableTable<&'static str, &'static str>, url: &str) -> Result<Option<String>, StorageError> {
+    Ok(u2c.get(url)?.map(|code| code.value().to_string()))
+}
+
+/// Stores `url` under a fresh code from `gen_code`, both ways round, and returns the code.
+fn insert_url(
+    c2u: &mut Table<&'static str, &'static str>,
+    u2c: &mut Table<&'static str, &'static str>,
+    deleted: &impl ReadableTable<&'static str, &'static str>,
+    url: &str,
+    gen_code: impl FnMut() -> String,
+) -> Result<String, StorageError> {
+    let code = unused_code(&*c2u, deleted, gen_code)?;
+    c2u.insert(code.as_str(), url)?;
+    u2c.insert(url, code.as_str())?;
+    Ok(code)
+}
+
+/// Whether a client's request id is safe to send back: hex digits or a uuid, and not too long.
+fn is_request_id(id: &str, max_len: usize) -> bool {
+    if id.is_empty() || id.len() > max_len {
+        return false;
+    }
+    let is_hex = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit());
+    let groups = id.split('-').collect::<Vec<_>>();
+    match groups.len() {
+        1 => is_hex(id),
+        5 => groups.iter().zip([8, 4, 4, 4, 12]).all(|(g, len)| g.len() == len && is_hex(g)),
+        _ => false,
+    }
+}
+
+fn gen_request_id(format: RequestIdFormat, len: usize) -> String {
+    let mut rng = rand::rng();
+    match format {
+        RequestIdFormat::Hex => (0..len).map(|_| format!("{:x}", rng.random_range(0..16u8))).collect(),
+        RequestIdFormat::Uuid => {
+            let mut bytes = [0u8; 16];
+            rng.fill(&mut bytes);
+            bytes[6] = (bytes[6] & 0x0f) | 0x40; // version 4
+            bytes[8] = (bytes[8] & 0x3f) | 0x80; // rfc 4122 variant
+            let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+            format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+        }
+        RequestIdFormat::Base62 => (0..len).map(|_| BASE62[rng.random_range(0..BASE62.len())] as char).collect(),
+    }
+}
+
+/// A new code with `prefix`, skipping any in `reserved` (from `--code-reserved`). Used for every generated code,
+/// which `unused_code` then checks against the routes and the database.
+fn new_code<R: RngExt + ?Sized>(prefix: &str, profile: CodeProfile, reserved: &HashSet<String>, rng: &mut R) -> String {
+    loop {
+        let key = gen_key(profile, rng);
+        let code = format!("{}{}", prefix, key);
+        if !reserved.contains(&key.to_ascii_lowercase()) && !reserved.contains(&code.to_ascii_lowercase()) {
+            return code;
+        }
     }
+}
 
-    println!("stored: {} -> {}", code.as_str(), url);
-    let j = Json(Response { ok: true, msg: code.to_string() }).into_response();
-    return (StatusCode::CREATED, [(LOCATION, format!("/{}", code))] , j).into_response();
+/// A random code (without any prefix). Takes the rng so `--seed` can make codes reproducible.
+fn gen_key<R: RngExt + ?Sized>(profile: CodeProfile, rng: &mut R) -> String {
+    match profile {
+        CodeProfile::Base64 => {
+            let mut bytes = [0u8; 4];
+            rng.fill(&mut bytes);
+            base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(bytes)
+        }
+        CodeProfile::Pronounceable => {
+            let mut code = String::with_capacity(PRONOUNCEABLE_SYLLABLES * 2);
+            for _ in 0..PRONOUNCEABLE_SYLLABLES {
+                code.push(CONSONANTS[rng.random_range(0..CONSONANTS.len())] as char);
+                code.push(VOWELS[rng.random_range(0..VOWELS.len())] as char);
+            }
+            code
+        }
+    }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_ids_are_unique() {
+        for format in [RequestIdFormat::Hex, RequestIdFormat::Uuid, RequestIdFormat::Base62] {
+            let ids = (0..10_000).map(|_| gen_request_id(format, 32)).collect::<HashSet<_>>();
+            assert_eq!(ids.len(), 10_000);
+        }
+    }
+
+    #[test]
+    fn generated_request_ids_are_accepted() {
+        assert!(is_request_id(&gen_request_id(RequestIdFormat::Hex, 32), 64));
+        assert!(is_request_id(&gen_request_id(RequestIdFormat::Uuid, 32), 64));
+    }
+
+    #[test]
+    fn request_id_format() {
+        assert!(is_request_id("0123456789abcdefABCDEF", 64));
+        assert!(is_request_id("123e4567-e89b-12d3-a456-426614174000", 64));
+        assert!(!is_request_id("", 64));
+        assert!(!is_request_id("abc", 2));
+        assert!(!is_request_id("xyz", 64));
+        assert!(!is_request_id("abc def", 64));
+        assert!(!is_request_id("abc\r\nx-evil: 1", 64));
+        assert!(!is_request_id("123e4567-e89b-12d3-a456", 64));
+        assert!(!is_request_id("123e4567e-89b-12d3-a456-426614174000", 64));
+        assert!(!is_request_id("123e4567--e89b-12d3-a456-426614174000", 64));
+    }
+
+    fn index_page() -> IndexPage {
+        IndexPage { html: "<html><body></body></html>".to_string(), modified: None }
+    }
+
+    fn index_with(name: HeaderName, value: &str) -> AxumResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        serve_index(&index_page(), IndexQuery { created: None }, &headers)
+    }
+
+    #[test]
+    fn index_etag_match_is_not_modified() {
+        let res = serve_index(&index_page(), IndexQuery { created: None }, &HeaderMap::new());
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers()[ETAG].to_str().unwrap().to_string();
+
+        assert_eq!(index_with(IF_NONE_MATCH, &etag).status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(index_with(IF_NONE_MATCH, &format!("W/{}", etag)).status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(index_with(IF_NONE_MATCH, &format!("\"other\", {}", etag)).status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(index_with(IF_NONE_MATCH, "*").status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[test]
+    fn index_etag_mismatch_is_ok() {
+        let res = index_with(IF_NONE_MATCH, "\"0000000000000000\"");
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().contains_key(ETAG));
+    }
+
+    #[test]
+    fn index_if_modified_since() {
+        let modified = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let index = IndexPage { modified: Some(modified), ..index_page() };
+        let mut headers = HeaderMap::new();
+
+        headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_str(&httpdate::fmt_http_date(modified)).unwrap());
+        let res = serve_index(&index, IndexQuery { created: None }, &headers);
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        let earlier = modified - Duration::from_secs(1);
+        headers.insert(IF_MODIFIED_SINCE, HeaderValue::from_str(&httpdate::fmt_http_date(earlier)).unwrap());
+        let res = serve_index(&index, IndexQuery { created: None }, &headers);
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+
+    #[test]
+    fn fix_code_path_cases() {
+        assert_eq!(fix_code_path("//abc").as_deref(), Some("/abc"));
+        assert_eq!(fix_code_path("///abc").as_deref(), Some("/abc"));
+        assert_eq!(fix_code_path("/abc.").as_deref(), Some("/abc"));
+        assert_eq!(fix_code_path("//ns/abc.").as_deref(), Some("/ns/abc"));
+        assert_eq!(fix_code_path("/abc"), None);
+        assert_eq!(fix_code_path("/ns/abc"), None);
+        assert_eq!(fix_code_path("/abc.."), None);
+        assert_eq!(fix_code_path("//a/b/c"), None);
+        assert_eq!(fix_code_path("//a%20b"), None);
+    }
+
+
+    #[test]
+    fn denied_schemes() {
+        assert_eq!(denied_scheme("javascript:alert(1)").as_deref(), Some("javascript"));
+        assert_eq!(denied_scheme("data:text/html,<script>alert(1)</script>").as_deref(), Some("data"));
+        assert_eq!(denied_scheme("vbscript:msgbox(1)").as_deref(), Some("vbscript"));
+        assert_eq!(denied_scheme("file:///etc/passwd").as_deref(), Some("file"));
+        assert_eq!(denied_scheme("  javascript :alert(1)").as_deref(), Some("javascript"));
+        for scheme in DENY_SCHEMES {
+            assert!(normalize_url(&format!("{}:x", scheme)).unwrap_err().starts_with("forbidden url scheme"));
+        }
+    }
 
-fn gen_key() -> String {
-    let mut bytes = [0u8; 4];
-    rand::rng().fill(&mut bytes);
-    return base64::prelude::BASE64_URL_SAFE_NO_PAD.encode(&bytes);
-}
\ No newline at end of file
+    #[test]
+    fn allowed_schemes_are_not_denied() {
+        assert_eq!(denied_scheme("http://example.com/"), None);
+        assert_eq!(denied_scheme("https://example.com/javascript:alert(1)"), None);
+        assert!(normalize_url("https://example.com/").is_ok());
+    }
+
+
+    #[test]
+    fn mixed_case_schemes() {
+        assert_eq!(denied_scheme("JaVaScRiPt:alert(1)").as_deref(), Some("javascript"));
+        assert_eq!(denied_scheme("DATA:text/html,x").as_deref(), Some("data"));
+        assert!(normalize_url("JaVaScRiPt:alert(1)").unwrap_err().starts_with("forbidden url scheme"));
+
+        assert_eq!(normalize_url("HTTPS://example.com/").unwrap().1, "https://example.com/");
+        assert_eq!(normalize_url("HtTp://example.com/a#B").unwrap().1, "http://example.com/a#B");
+        assert_eq!(denied_scheme("HTTPS://example.com/"), None);
+    }
+
+
+    #[test]
+    fn url_chars() {
+        assert!(check_url_chars("https://example.com/a?b=c").is_ok());
+        assert!(check_url_chars("https://example.com/\r\n").is_err());
+        assert!(check_url_chars("https://example.com/a\r\nLocation: x").is_err());
+        assert!(check_url_chars("https://example.com/a b").is_err());
+        assert!(check_url_chars("https://example.com/a\tb").is_err());
+        assert!(check_url_chars("https://example.com/\0").is_err());
+    }
+
+
+    #[test]
+    fn hostless_urls() {
+        assert!(normalize_url("https://").is_err());
+        assert!(normalize_url("https:///foo").is_err());
+        assert!(normalize_url("https://example.com/foo").is_ok());
+    }
+
+
+    #[test]
+    fn put_statuses() {
+        assert_eq!(put_status(PutStatus::Split, true), StatusCode::CREATED);
+        assert_eq!(put_status(PutStatus::Split, false), StatusCode::OK);
+        assert_eq!(put_status(PutStatus::Ok, true), StatusCode::OK);
+        assert_eq!(put_status(PutStatus::Ok, false), StatusCode::OK);
+        assert_eq!(put_status(PutStatus::Created, true), StatusCode::CREATED);
+        assert_eq!(put_status(PutStatus::Created, false), StatusCode::CREATED);
+    }
+
+
+    fn memory_db() -> Database {
+        let db = Database::builder().create_with_backend(redb::backends::InMemoryBackend::new()).unwrap();
+        init_tables(&db).unwrap();
+        db
+    }
+
+    /// Stores `url` in `db` with codes from a `StdRng` seeded with `seed`, like `--seed` does.
+    fn put(db: &Database, url: &str, seed: u64) -> (String, bool) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let wr = db.begin_write().unwrap();
+        let result = {
+            let mut c2u = wr.open_table(*CODE_TO_URL).unwrap();
+            let mut u2c = wr.open_table(*URL_TO_CODE).unwrap();
+            let deleted = wr.open_table(*DELETED).unwrap();
+            match stored_code(&u2c, url).unwrap() {
+                Some(code) => (code, false),
+                None => {
+                    let code = insert_url(&mut c2u, &mut u2c, &deleted, url, || gen_key(CodeProfile::Base64, &mut rng));
+                    (code.unwrap(), true)
+                }
+            }
+        };
+        wr.commit().unwrap();
+        result
+    }
+
+    #[test]
+    fn put_creates_then_dedups() {
+        let db = memory_db();
+        let (code, created) = put(&db, "https://example.com/a", 1);
+        assert!(created);
+        assert_eq!(put(&db, "https://example.com/a", 2), (code.clone(), false));
+
+        let rd = db.begin_read().unwrap();
+        assert_eq!(rd.open_table(*CODE_TO_URL).unwrap().get(code.as_str()).unwrap().unwrap().value(), "https://example.com/a");
+        assert_eq!(rd.open_table(*URL_TO_CODE).unwrap().get("https://example.com/a").unwrap().unwrap().value(), code);
+    }
+
+    #[test]
+    fn seeded_codes_are_reproducible() {
+        let (a, _) = put(&memory_db(), "https://example.com/a", 42);
+        let (b, _) = put(&memory_db(), "https://example.com/a", 42);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn put_skips_taken_and_tombstoned_codes() {
+        let db = memory_db();
+        let (first, _) = put(&db, "https://example.com/a", 7);
+        let (second, created) = put(&db, "https://example.com/b", 7);
+        assert!(created);
+        assert_ne!(first, second);
+
+        let wr = db.begin_write().unwrap();
+        {
+            let mut c2u = wr.open_table(*CODE_TO_URL).unwrap();
+            let mut u2c = wr.open_table(*URL_TO_CODE).unwrap();
+            c2u.remove(first.as_str()).unwrap();
+            u2c.remove("https://example.com/a").unwrap();
+            wr.open_table(*DELETED).unwrap().insert(first.as_str(), "https://example.com/a").unwrap();
+        }
+        wr.commit().unwrap();
+
+        let (third, _) = put(&db, "https://example.com/c", 7);
+        assert_ne!(third, first);
+    }
+
+    #[test]
+    fn generated_codes_skip_routes() {
+        let db = memory_db();
+        let wr = db.begin_write().unwrap();
+        let mut c2u = wr.open_table(*CODE_TO_URL).unwrap();
+        let mut u2c = wr.open_table(*URL_TO_CODE).unwrap();
+        let deleted = wr.open_table(*DELETED).unwrap();
+        let mut codes = ["put", "health", "abc"].into_iter().map(String::from);
+        let code = insert_url(&mut c2u, &mut u2c, &deleted, "https://example.com/", || codes.next().unwrap());
+        assert_eq!(code.unwrap(), "abc");
+    }
+
+
+    #[test]
+    fn cache_drops_least_recently_used() {
+        let cache = RedirectCache::new(2, Duration::from_secs(60));
+        cache.insert("a", "https://example.com/a", None);
+        cache.insert("b", "https://example.com/b", Some(false));
+        assert!(cache.get("a").is_some());
+
+        cache.insert("c", "https://example.com/c", None);
+        assert_eq!(cache.get("a"), Some(("https://example.com/a".to_string(), None)));
+        assert_eq!(cache.get("b"), None);
+        assert!(cache.get("c").is_some());
+
+        // re-inserting an entry doesn't evict anything else
+        cache.insert("c", "https://example.com/c2", None);
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.get("c"), Some(("https://example.com/c2".to_string(), None)));
+    }
+
+    #[test]
+    fn cache_entries_expire() {
+        let cache = RedirectCache::new(2, Duration::ZERO);
+        cache.insert("a", "https://example.com/a", None);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.misses.load(Ordering::Relaxed), 1);
+    }
+
+
+    #[test]
+    fn token_bucket_batches_go_into_debt() {
+        let mut bucket = TokenBucket::new(10);
+        assert!(bucket.take_n(100).is_ok());
+        let wait = bucket.take().unwrap_err();
+        assert!(wait > Duration::from_secs(9));
+    }
+
+
+    #[test]
+    fn new_code_skips_reserved() {
+        let reserved = HashSet::from(["dbca".to_string()]);
+        let mut rng = StdRng::seed_from_u64(3);
+        let first = new_code("", CodeProfile::Pronounceable, &HashSet::new(), &mut rng);
+
+        let reserved_first = HashSet::from([first.clone()]);
+        let mut rng = StdRng::seed_from_u64(3);
+        assert_ne!(new_code("", CodeProfile::Pronounceable, &reserved_first, &mut rng), first);
+
+        // with a prefix, both the key and the full code are checked
+        let mut rng = StdRng::seed_from_u64(3);
+        let prefixed = HashSet::from([format!("x-{}", first)]);
+        assert_ne!(new_code("x-", CodeProfile::Pronounceable, &prefixed, &mut rng), format!("x-{}", first));
+        let mut rng = StdRng::seed_from_u64(3);
+        assert_eq!(new_code("x-", CodeProfile::Pronounceable, &reserved, &mut rng), format!("x-{}", first));
+    }
+
+
+    #[test]
+    fn self_hosts_include_base_url() {
+        let bind = "0.0.0.0:8080".parse().unwrap();
+        let hosts = self_hosts(bind, None);
+        assert!(hosts.contains(&("localhost".to_string(), 8080)));
+        assert!(hosts.contains(&("[::1]".to_string(), 8080)));
+        assert!(!hosts.contains(&("cc.example.com".to_string(), 443)));
+
+        let base = url::Url::parse("https://CC.example.com/s/").unwrap();
+        let hosts = self_hosts(bind, Some(&base));
+        assert!(hosts.contains(&("cc.example.com".to_string(), 443)));
+        assert!(hosts.contains(&("0.0.0.0".to_string(), 8080)));
+
+        let base = url::Url::parse("http://cc.example.com:8000").unwrap();
+        assert!(self_hosts(bind, Some(&base)).contains(&("cc.example.com".to_string(), 8000)));
+    }
+
+
+    #[tokio::test]
+    async fn mirror_catches_up_at_startup() {
+        let db = memory_db();
+        put(&db, "https://example.com/a", 1);
+        let (kept, _) = put(&db, "https://example.com/b", 2);
+
+        let path = std::env::temp_dir().join(format!("cc-mirror-test-{}.sqlite", std::process::id()));
+        let _ = fs::remove_file(&path);
+        {
+            let conn = rusqlite::Connection::open(&path).unwrap();
+            conn.execute("CREATE TABLE mappings (code TEXT PRIMARY KEY, url TEXT NOT NULL)", ()).unwrap();
+            conn.execute("INSERT INTO mappings (code, url) VALUES ('gone', 'https://example.com/gone')", ()).unwrap();
+            conn.execute("INSERT INTO mappings (code, url) VALUES (?1, 'https://example.com/old')", (&kept,)).unwrap();
+        }
+
+        drop(SqliteMirror::start(&path, &db).unwrap());
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let mut rows = conn.prepare("SELECT code, url FROM mappings ORDER BY url").unwrap()
+            .query_map((), |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))).unwrap()
+            .collect::<Result<Vec<_>, _>>().unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows.pop().unwrap(), (kept, "https://example.com/b".to_string()));
+        assert_eq!(rows.pop().unwrap().1, "https://example.com/a");
+    }
+}